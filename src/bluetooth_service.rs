@@ -1,138 +1,314 @@
-use tokio::{self, sync};
-use hap::futures::StreamExt;
-use bluer::{
-    AdapterEvent,
-    Device
-};
+use std::future::Future;
+use tokio::{self, sync, time::{Duration, Instant}};
+use tracing::{info, warn};
+use bluer::Device;
 
 use crate::{
     Result,
-    utils::{Temperature, HeatingCoolingState},
-    bluetooth_service::worker::Worker,
+    metrics::metrics,
+    utils::{DeviceState, Temperature, HeatingCoolingState},
+    bluetooth_service::{
+        discovery::{discover_volcano, DeviceSelector},
+        worker::Worker,
+        read_cache::ReadCache,
+        targ_temp_debouncer::TargTempDebouncer,
+    },
 };
 
+pub mod discovery;
+mod gatt_resolver;
 mod worker;
-mod targ_temp_worker;
-mod curr_temp_worker;
-mod heat_air_worker;
+mod request_queue;
+mod read_cache;
+mod targ_temp_debouncer;
+mod tracked_value;
+mod targ_temp_char;
+mod curr_temp_char;
+mod heat_air_chars;
 
 
 #[derive(Debug)]
-pub enum Message {
+pub(crate) enum Message {
     GetCurrTemp { resp_tx: sync::oneshot::Sender<Temperature> },
     GetTargTemp  { resp_tx: sync::oneshot::Sender<Temperature> },
     GetHeatAirState { resp_tx: sync::oneshot::Sender<HeatingCoolingState> },
     SetTargTemp { temp: Temperature, resp_tx: sync::oneshot::Sender<bluer::Result<()>> },
     SetHeatAirState { state: HeatingCoolingState, resp_tx: sync::oneshot::Sender<bluer::Result<()>> },
+    GetConnected { resp_tx: sync::oneshot::Sender<bool> },
     Disconnect { resp_tx: sync::oneshot::Sender<bluer::Result<()>> },
 }
 
 
+/// A connection to one volcano.
+///
+/// Everything goes through a single connection task, so the service can be
+/// shared (e.g. in an `Arc`) and called from anywhere. Reads are cached for
+/// a short while, writes to the target temperature are debounced.
 pub struct BluetoothService {
     tx: sync::mpsc::Sender<Message>,
+    targ_temp_debouncer: TargTempDebouncer,
+    curr_temp_cache: ReadCache<Temperature>,
+    targ_temp_cache: ReadCache<Temperature>,
+    heat_air_cache: ReadCache<HeatingCoolingState>,
+    state_tx: sync::watch::Sender<DeviceState>,
 }
 
 impl BluetoothService {
 
-    pub async fn new() -> Result<BluetoothService> {
-        let volcano = Self::discover_volcano()
+    /// Scans for a volcano matching the selector and connects to it, see
+    /// [`BluetoothService::connect`].
+    pub async fn new(selector: &DeviceSelector, read_ttl: Duration) -> Result<BluetoothService> {
+        let volcano = discover_volcano(selector)
                             .await?
-                            .expect("couldn't find the volcano!");
-        println!("{:?}", volcano.name().await);
+                            .ok_or("couldn't find the volcano")?;
+        info!(device = %volcano.address(),
+              name = ?volcano.name().await.ok().flatten(),
+              "found volcano");
+        Self::connect(volcano, read_ttl).await
+    }
+
+    /// Connects to an already discovered volcano. Reads younger than
+    /// `read_ttl` are answered from cache without going out over bluetooth.
+    pub async fn connect(volcano: Device, read_ttl: Duration) -> Result<BluetoothService> {
+        let (tx, mut rx) = sync::mpsc::channel(32);
+        let mut worker = Worker::new(volcano).await?;
+        tokio::spawn(async move {
+            worker.run_loop(&mut rx).await;
+        });
+        Ok(Self::with_worker(tx, read_ttl, DeviceState::default()))
+    }
+
+    /// Returns right away and keeps calling `find` in the background until
+    /// it turns up a volcano that can be connected to. Until then the
+    /// service reports disconnected, reads return `None` and writes fail.
+    ///
+    /// `last_known` is what the volcano showed before, e.g. on the last
+    /// run, with [`stale_since`](DeviceState::stale_since) set to when that
+    /// was. The state starts out with it, the first successful read drops
+    /// all of it. Pass `DeviceState::default()` if there's nothing to go on.
+    pub fn start<F, Fut>(find: F,
+                         read_ttl: Duration,
+                         last_known: DeviceState) -> BluetoothService
+    where F: FnMut() -> Fut + Send + 'static,
+          Fut: Future<Output = Result<Device>> + Send + 'static {
         let (tx, rx) = sync::mpsc::channel(32);
-        let mut worker = Worker::new(volcano, rx).await?;
         tokio::spawn(async move {
-            worker.run_loop().await;
+            worker::find_and_run(find, rx).await;
         });
-        let service = BluetoothService { tx };
-        Ok(service)
+        Self::with_worker(tx, read_ttl, last_known)
+    }
+
+    /// Everything we last heard from the volcano. Every read that goes
+    /// through the service keeps this up to date.
+    pub fn state(&self) -> DeviceState {
+        *self.state_tx.borrow()
+    }
+
+    /// Notified whenever any part of [`BluetoothService::state`] changes.
+    /// Watching this doesn't cost any bluetooth traffic of its own.
+    pub fn subscribe(&self) -> sync::watch::Receiver<DeviceState> {
+        self.state_tx.subscribe()
+    }
+
+    /// When the least recently read value was last read off the volcano,
+    /// by anyone. `None` if one of them never was, or was written since.
+    pub fn read_at(&self) -> Option<Instant> {
+        let curr_temp_read_at = self.curr_temp_cache.read_at()?;
+        let targ_temp_read_at = self.targ_temp_cache.read_at()?;
+        let heat_air_read_at = self.heat_air_cache.read_at()?;
+        Some(curr_temp_read_at.min(targ_temp_read_at).min(heat_air_read_at))
+    }
+
+    /// Reads everything once and returns the updated state. Something has
+    /// to call this regularly for subscribers to see changes made on the
+    /// device itself.
+    pub async fn poll(&self) -> DeviceState {
+        tokio::join!(self.get_curr_heat_air_state(),
+                     self.get_curr_temp(),
+                     self.get_targ_temp(),
+                     self.is_connected());
+        self.state()
     }
 
+    /// Disconnects and stops the connection task, every call after
+    /// this one fails. A target temperature that's still being debounced
+    /// gets written first.
     pub async fn disconnect(&self) -> Result<()> {
+        if let Some(Err(err)) = self.targ_temp_debouncer.flush().await {
+            warn!(error = %err, "pending target temp write failed before disconnecting");
+        }
         let (resp_tx, resp_rx) = sync::oneshot::channel();
         let message = Message::Disconnect { resp_tx };
-        self.tx.send(message).await.expect("couldn't send message");
+        self.send(message).await;
         match resp_rx.await {
             Ok(_) => Ok(()),
             Err(err) => Err(Box::new(err)),
         }
     }
 
+    /// Whether the bluetooth link is up, without forcing a connection.
+    pub async fn is_connected(&self) -> bool {
+        let (resp_tx, resp_rx) = sync::oneshot::channel();
+        let message = Message::GetConnected { resp_tx };
+        self.send(message).await;
+        let connected = resp_rx.await.unwrap_or(false);
+        self.update_state(|state| state.connected = connected);
+        connected
+    }
+
+    /// `None` once the connection task is gone.
     pub async fn get_curr_temp(&self) -> Option<Temperature> {
+        if let Some(curr_temp) = self.curr_temp_cache.fresh() {
+            return Some(curr_temp);
+        }
         let (resp_tx, resp_rx) = sync::oneshot::channel();
         let message = Message::GetCurrTemp { resp_tx };
-        self.tx.send(message).await.expect("couldn't send message");
+        self.send(message).await;
         match resp_rx.await {
-            Ok(curr_temp) => Some(curr_temp),
+            Ok(curr_temp) => {
+                self.curr_temp_cache.store(curr_temp);
+                self.update_state(|state| {
+                    state.apply_read(|state| state.curr_temp = Some(curr_temp));
+                });
+                Some(curr_temp)
+            },
             Err(_) => None,
         }
     }
 
+    /// Includes a target temperature that's still waiting to be written.
     pub async fn get_targ_temp(&self) -> Option<Temperature> {
+        if let Some(pending_temp) = self.targ_temp_debouncer.pending() {
+            return Some(pending_temp);
+        }
+        if let Some(targ_temp) = self.targ_temp_cache.fresh() {
+            return Some(targ_temp);
+        }
         let (resp_tx, resp_rx) = sync::oneshot::channel();
         let message = Message::GetTargTemp { resp_tx };
-        self.tx.send(message).await.expect("couldn't send message");
+        self.send(message).await;
         match resp_rx.await {
-            Ok(targ_temp) => Some(targ_temp),
+            Ok(targ_temp) => {
+                self.targ_temp_cache.store(targ_temp);
+                self.update_state(|state| {
+                    state.apply_read(|state| state.targ_temp = Some(targ_temp));
+                });
+                Some(targ_temp)
+            },
             Err(_) => None,
         }
     }
 
+    /// The target temperature without asking the volcano: one still
+    /// waiting to be written, or else the last one read.
+    pub fn known_targ_temp(&self) -> Option<Temperature> {
+        self.targ_temp_debouncer
+            .pending()
+            .or(self.state().targ_temp)
+    }
+
+    /// Debounced, this only queues the write. Fast successive calls (someone
+    /// dragging a dial) end up as a single write of the last value.
+    ///
+    /// Fire and forget: `Some(())` means queued, not written. A write that
+    /// fails later is only logged, and the next read brings the old value
+    /// back. Use [`Self::write_temp`] to wait for the outcome.
+    ///
+    /// [`Self::get_targ_temp`] reports the pending temp right away, the
+    /// [`state`](Self::state) only once it's been written and read back, so
+    /// subscribers never see the values a dial passes through on the way.
     pub async fn set_temp(&self, temp: Temperature) -> Option<()> {
-        let (resp_tx, resp_rx) = sync::oneshot::channel();
-        let message = Message::SetTargTemp { temp, resp_tx };
-        self.tx.send(message).await.expect("couldn't send message");
-        match resp_rx.await {
-            Ok(_) => Some(()),
-            Err(_) => None,
+        if self.tx.is_closed() {
+            return None;
+        }
+        self.targ_temp_cache.invalidate();
+        self.targ_temp_debouncer.set(temp);
+        Some(())
+    }
+
+    /// Like [`Self::set_temp`], but writes right away (along with anything
+    /// still being debounced) and waits for the volcano to take it. The
+    /// state has it once it has.
+    pub async fn write_temp(&self, temp: Temperature) -> Option<bluer::Result<()>> {
+        self.set_temp(temp).await?;
+        let result = self.targ_temp_debouncer.flush().await;
+        // unless someone set another one meanwhile
+        if matches!(result, Some(Ok(()))) && self.targ_temp_debouncer.pending().is_none() {
+            self.update_state(|state| state.apply_read(|state| state.targ_temp = Some(temp)));
         }
+        result
     }
 
+    /// The volcano only pumps heated air, [`HeatingCoolingState::Cooling`]
+    /// means heat and air.
     pub async fn get_curr_heat_air_state(&self) -> Option<HeatingCoolingState> {
+        if let Some(heat_air_state) = self.heat_air_cache.fresh() {
+            return Some(heat_air_state);
+        }
         let (resp_tx, resp_rx) = sync::oneshot::channel();
         let message = Message::GetHeatAirState { resp_tx };
-        self.tx.send(message).await.expect("couldn't send message");
+        self.send(message).await;
         match resp_rx.await {
-            Ok(heat_air_state) => Some(heat_air_state),
+            Ok(heat_air_state) => {
+                self.heat_air_cache.store(heat_air_state);
+                self.update_state(|state| {
+                    state.apply_read(|state| state.heat_air_state = Some(heat_air_state));
+                });
+                Some(heat_air_state)
+            },
             Err(_) => None,
         }
     }
 
-    pub async fn set_curr_heat_air_state(&self, state: HeatingCoolingState) -> Option<()> {
+    /// Returns once the write went out with how it went, `None` if the
+    /// connection task is gone.
+    pub async fn set_curr_heat_air_state(&self,
+                                         state: HeatingCoolingState) -> Option<bluer::Result<()>> {
         let (resp_tx, resp_rx) = sync::oneshot::channel();
         let message = Message::SetHeatAirState { state, resp_tx };
-        self.tx.send(message).await.expect("couldn't send message");
-        match resp_rx.await {
-            Ok(_) => Some(()),
-            Err(_) => None,
-        }
+        self.send(message).await;
+        let resp = resp_rx.await;
+        self.heat_air_cache.invalidate();
+        resp.ok()
     }
 }
 
 impl BluetoothService {
 
-    async fn discover_volcano() -> Result<Option<Device>> {
-        let session = bluer::Session::new().await?;
-        let adapter = session.default_adapter().await?;
-        adapter.set_powered(true).await?;
-        {
-            let mut device_stream = adapter.discover_devices().await?;
-            while let Some(evt) = device_stream.next().await {
-                match evt {
-                    AdapterEvent::DeviceAdded(addr) => {
-                        let device = adapter.device(addr)?;
-                        if device.name()
-                                 .await?
-                                 .unwrap_or("".into())
-                                 .contains("VOLCANO") {
-                            return Ok(Some(device));
-                        }
-                        
-                    },
-                    _ => (),
-                }
-            }
+    fn with_worker(tx: sync::mpsc::Sender<Message>,
+                   read_ttl: Duration,
+                   last_known: DeviceState) -> BluetoothService {
+        let targ_temp_debouncer = TargTempDebouncer::new(tx.clone());
+        let initial_state = DeviceState {
+            connected: false,
+            ..last_known
+        };
+        BluetoothService {
+            tx,
+            targ_temp_debouncer,
+            curr_temp_cache: ReadCache::new(read_ttl),
+            targ_temp_cache: ReadCache::new(read_ttl),
+            heat_air_cache: ReadCache::new(read_ttl),
+            state_tx: sync::watch::channel(initial_state).0,
+        }
+    }
+
+    // subscribers only hear about it if something actually changed
+    fn update_state(&self, modify: impl FnOnce(&mut DeviceState)) {
+        self.state_tx.send_if_modified(|state| {
+            let prev_state = *state;
+            modify(state);
+            *state != prev_state
+        });
+    }
+
+    // if the worker is gone the message gets dropped along with its
+    // resp_tx, so callers see their usual None/Err instead of a panic
+    async fn send(&self, message: Message) {
+        if self.tx.send(message).await.is_err() {
+            warn!("bluetooth worker is gone, dropping message");
         }
-        Ok(None)
+        let depth = self.tx.max_capacity() - self.tx.capacity();
+        metrics().set_queue_depth("service", depth);
     }
 }
@@ -0,0 +1,44 @@
+use bluer::gatt::remote::Characteristic;
+use tracing::{instrument, warn};
+
+use crate::{
+    metrics::metrics,
+    utils::Temperature,
+};
+
+// handles reading the current temp
+
+pub struct CurrTempChar {
+    curr_temp_char: Characteristic,
+}
+
+impl CurrTempChar {
+
+    pub fn new(curr_temp_char: Characteristic) -> CurrTempChar {
+        CurrTempChar { curr_temp_char }
+    }
+
+    // `None` when the read failed, nothing made up stands in for it
+    pub async fn read(&mut self) -> Option<Temperature> {
+        self.get_curr_temp()
+            .await
+    }
+
+    #[instrument(level = "debug", name = "ble_read", skip(self), fields(characteristic = "curr_temp"))]
+    async fn get_curr_temp(&self) -> Option<Temperature> {
+        let timer = metrics().start_ble_read("curr_temp");
+        let curr_temp = self.curr_temp_char
+                            .read()
+                            .await
+                            .map_err(|err| warn!(error = %err, "read failed"))
+                            .ok()
+                            .map(|raw_temp| {
+                                Temperature::from_device_val(raw_temp)
+                            });
+        timer.observe_duration();
+        if let Some(curr_temp) = curr_temp {
+            metrics().set_curr_temp(curr_temp);
+        }
+        curr_temp
+    }
+}
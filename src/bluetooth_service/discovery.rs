@@ -0,0 +1,223 @@
+use tokio::time::{Duration, Instant};
+use tokio_stream::{Stream, StreamExt};
+use tracing::{debug, info, warn};
+use bluer::{
+    Adapter,
+    Address,
+    AdapterEvent,
+    Device,
+    Session,
+};
+
+use crate::{
+    Result,
+    bluetooth_service::gatt_resolver::{
+        GattResolver,
+        ResolvedChars,
+        FIRMWARE_CHAR_UUID,
+        MODEL_CHAR_UUID,
+        SERIAL_CHAR_UUID,
+    },
+};
+
+// how long to keep listening for more volcanos once the first one showed
+// up, when we're after the strongest signal
+const RSSI_SCAN_DURATION: Duration = Duration::from_secs(5);
+
+/// How long [`read_device_info`] gets before a volcano counts as
+/// unreachable, a quick connect shouldn't hold up the others.
+pub const INFO_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// What the volcano says about itself, each one `None` if it
+/// doesn't have the characteristic.
+#[derive(Debug, Clone, Default)]
+pub struct DeviceInfo {
+    pub model: Option<String>,
+    pub serial: Option<String>,
+    pub firmware: Option<String>,
+}
+
+/// Which volcano to pick when there's more than one in range. The default
+/// takes the first one that shows up.
+#[derive(Debug, Clone, Default)]
+pub struct DeviceSelector {
+    /// Scan on this adapter, by name (hci1) or address, instead of the
+    /// default one.
+    pub adapter: Option<String>,
+    /// Only this bluetooth address.
+    pub address: Option<Address>,
+    /// Only the volcano with this serial number, checking it means
+    /// connecting to every candidate.
+    pub serial: Option<String>,
+    /// Keep scanning for a few seconds and take the strongest signal.
+    pub strongest_rssi: bool,
+}
+
+impl DeviceSelector {
+
+    // a candidate we can't read the serial off is skipped, not the end
+    // of the whole search, it may just be out of range by now
+    async fn matches(&self, device: &Device) -> bool {
+        if self.address.map_or(false, |address| address != device.address()) {
+            return false;
+        }
+        if let Some(serial) = &self.serial {
+            let device_serial = match tokio::time::timeout(INFO_TIMEOUT,
+                                                           read_device_info(device)).await {
+                Ok(Ok(info)) => info.serial,
+                Ok(Err(err)) => {
+                    warn!(device = %device.address(), error = %err, "couldn't read serial, skipping");
+                    None
+                },
+                Err(_) => {
+                    warn!(device = %device.address(), "timed out reading serial, skipping");
+                    None
+                },
+            };
+            debug!(device = %device.address(), serial = ?device_serial, "read serial");
+            if device_serial.as_ref() != Some(serial) {
+                // don't sit on someone else's volcano
+                let _ = device.disconnect().await;
+                return false;
+            }
+        }
+        true
+    }
+}
+
+/// Scans for a volcano matching the selector. Only returns `None` if
+/// the scan itself ends, otherwise it keeps going until one shows up.
+pub async fn discover_volcano(selector: &DeviceSelector) -> Result<Option<Device>> {
+    let adapter = powered_adapter(selector.adapter.as_deref()).await?;
+    let mut device_stream = adapter.discover_devices().await?;
+    let mut candidates = Vec::new();
+    let mut scan_until: Option<Instant> = None;
+    while let Some(device) = next_volcano(&adapter, &mut device_stream, scan_until).await? {
+        if !selector.matches(&device).await {
+            continue;
+        }
+        if !selector.strongest_rssi {
+            return Ok(Some(device));
+        }
+        scan_until.get_or_insert_with(|| Instant::now() + RSSI_SCAN_DURATION);
+        candidates.push(device);
+    }
+
+    let mut strongest: Option<(Device, i16)> = None;
+    for device in candidates {
+        let rssi = device.rssi()
+                         .await?
+                         .unwrap_or(i16::MIN);
+        debug!(device = %device.address(), rssi, "candidate");
+        if strongest.as_ref().map_or(true, |(_, max_rssi)| rssi > *max_rssi) {
+            strongest = Some((device, rssi));
+        }
+    }
+    if let Some((device, rssi)) = &strongest {
+        info!(device = %device.address(), rssi, "picked the strongest volcano");
+    }
+    Ok(strongest.map(|(device, _)| device))
+}
+
+/// A volcano bluez already knows about, e.g. from an earlier run, without
+/// scanning. `None` if bluez has forgotten it.
+pub async fn known_volcano(adapter: Option<&str>, address: Address) -> Result<Option<Device>> {
+    let adapter = powered_adapter(adapter).await?;
+    if !adapter.device_addresses().await?.contains(&address) {
+        return Ok(None);
+    }
+    Ok(Some(adapter.device(address)?))
+}
+
+/// Every volcano that shows up within `duration`, including ones bluez
+/// already knows about.
+pub async fn scan_volcanos(adapter: Option<&str>, duration: Duration) -> Result<Vec<Device>> {
+    let adapter = powered_adapter(adapter).await?;
+    let mut device_stream = adapter.discover_devices().await?;
+    let scan_until = Instant::now() + duration;
+    let mut volcanos: Vec<Device> = Vec::new();
+    while let Some(device) = next_volcano(&adapter, &mut device_stream, Some(scan_until)).await? {
+        if volcanos.iter().all(|volcano| volcano.address() != device.address()) {
+            volcanos.push(device);
+        }
+    }
+    Ok(volcanos)
+}
+
+/// Reads the model, serial and firmware. They live in gatt
+/// characteristics, so this connects if it has to.
+pub async fn read_device_info(device: &Device) -> Result<DeviceInfo> {
+    if !device.is_connected().await? {
+        device.connect().await?;
+    }
+    let resolved = GattResolver::shared().resolve(device).await?;
+    Ok(DeviceInfo {
+        model: read_string(&resolved, MODEL_CHAR_UUID).await?,
+        serial: read_string(&resolved, SERIAL_CHAR_UUID).await?,
+        firmware: read_string(&resolved, FIRMWARE_CHAR_UUID).await?,
+    })
+}
+
+async fn read_string(resolved: &ResolvedChars, uuid: &'static str) -> Result<Option<String>> {
+    let characteristic = match resolved.get(uuid) {
+        Ok(characteristic) => characteristic,
+        Err(_) => return Ok(None),
+    };
+    let raw_val = characteristic.read().await?;
+    Ok(Some(String::from_utf8_lossy(&raw_val)
+                   .trim_matches(char::from(0))
+                   .trim()
+                   .to_string()))
+}
+
+/// The adapter by name (hci1) or address, the default adapter for `None`.
+pub async fn find_adapter(session: &Session, adapter: Option<&str>) -> Result<Adapter> {
+    let wanted = match adapter {
+        Some(wanted) => wanted,
+        None => return Ok(session.default_adapter().await?),
+    };
+    let wanted_address = wanted.parse::<Address>().ok();
+    for name in session.adapter_names().await? {
+        let adapter = session.adapter(&name)?;
+        if name == wanted || Some(adapter.address().await?) == wanted_address {
+            return Ok(adapter);
+        }
+    }
+    Err(format!("no bluetooth adapter {}", wanted).into())
+}
+
+async fn powered_adapter(adapter: Option<&str>) -> Result<Adapter> {
+    let session = Session::new().await?;
+    let adapter = find_adapter(&session, adapter).await?;
+    adapter.set_powered(true).await?;
+    Ok(adapter)
+}
+
+// the next device with a volcano's name, None once the scan
+// ends or runs past `until`
+async fn next_volcano(adapter: &Adapter,
+                      device_stream: &mut (impl Stream<Item = AdapterEvent> + Unpin),
+                      until: Option<Instant>) -> Result<Option<Device>> {
+    loop {
+        let evt = match until {
+            Some(until) => match tokio::time::timeout_at(until, device_stream.next()).await {
+                Ok(evt) => evt,
+                Err(_) => return Ok(None),
+            },
+            None => device_stream.next().await,
+        };
+        let addr = match evt {
+            Some(AdapterEvent::DeviceAdded(addr)) => addr,
+            Some(_) => continue,
+            None => return Ok(None),
+        };
+
+        let device = adapter.device(addr)?;
+        let name = device.name()
+                         .await?
+                         .unwrap_or_default();
+        if name.contains("VOLCANO") {
+            return Ok(Some(device));
+        }
+    }
+}
@@ -0,0 +1,202 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::{Arc, Mutex, OnceLock},
+};
+use tokio::time::{Duration, Instant};
+use bluer::{
+    gatt::remote::Characteristic,
+    Address,
+    Device,
+};
+use tracing::{debug, warn};
+
+// finds the volcano's gatt characteristics by uuid. a full walk of every
+// service and characteristic is a lot of dbus round trips, so the ids it
+// turns up are kept per device and tried first on the next connect. the
+// cache is one for the whole process, discovery reading the serial and the
+// worker connecting right after both go through it
+
+const SERVICE1_UUID: &str = "10100000-5354-4f52-5a26-4249434b454c";
+const SERVICE2_UUID: &str = "10110000-5354-4f52-5a26-4249434b454c";
+pub const FIRMWARE_CHAR_UUID: &str = "10100005-5354-4f52-5a26-4249434b454c";
+pub const SERIAL_CHAR_UUID: &str = "10100008-5354-4f52-5a26-4249434b454c";
+pub const MODEL_CHAR_UUID: &str = "10100007-5354-4f52-5a26-4249434b454c";
+pub const CURR_TEMP_CHAR_UUID: &str = "10110001-5354-4f52-5a26-4249434b454c";
+pub const TARG_TEMP_CHAR_UUID: &str = "10110003-5354-4f52-5a26-4249434b454c";
+pub const IS_HEATORAIR_ENABLED_CHAR_UUID: &str = "1010000c-5354-4f52-5a26-4249434b454c";
+pub const START_HEAT_CHAR_UUID: &str = "1011000f-5354-4f52-5a26-4249434b454c";
+pub const STOP_HEAT_CHAR_UUID: &str = "10110010-5354-4f52-5a26-4249434b454c";
+pub const START_AIR_CHAR_UUID: &str = "10110013-5354-4f52-5a26-4249434b454c";
+pub const STOP_AIR_CHAR_UUID: &str = "10110014-5354-4f52-5a26-4249434b454c";
+
+// everything we look for, with a name for the logs
+const KNOWN_CHARS: &[(&str, &str)] = &[
+    ("firmware", FIRMWARE_CHAR_UUID),
+    ("serial", SERIAL_CHAR_UUID),
+    ("model", MODEL_CHAR_UUID),
+    ("curr_temp", CURR_TEMP_CHAR_UUID),
+    ("targ_temp", TARG_TEMP_CHAR_UUID),
+    ("heat_or_air_enabled", IS_HEATORAIR_ENABLED_CHAR_UUID),
+    ("start_heat", START_HEAT_CHAR_UUID),
+    ("stop_heat", STOP_HEAT_CHAR_UUID),
+    ("start_air", START_AIR_CHAR_UUID),
+    ("stop_air", STOP_AIR_CHAR_UUID),
+];
+
+// bluez resolves the services a little after connecting
+const SERVICES_RESOLVE_TIMEOUT: Duration = Duration::from_secs(5);
+const SERVICES_RESOLVE_POLL: Duration = Duration::from_millis(100);
+
+// a characteristic this volcano (or its firmware) doesn't have
+#[derive(Debug, Clone, PartialEq)]
+pub struct MissingCharacteristic {
+    pub name: &'static str,
+    pub uuid: &'static str,
+}
+
+impl fmt::Display for MissingCharacteristic {
+
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "the volcano has no {} characteristic ({})", self.name, self.uuid)
+    }
+}
+
+impl std::error::Error for MissingCharacteristic {}
+
+// writes to something that isn't there fail like any other ble write
+impl From<MissingCharacteristic> for bluer::Error {
+
+    fn from(missing: MissingCharacteristic) -> bluer::Error {
+        bluer::Error {
+            kind: bluer::ErrorKind::NotSupported,
+            message: missing.to_string(),
+        }
+    }
+}
+
+pub struct ResolvedChars {
+    chars: HashMap<&'static str, Characteristic>,
+}
+
+impl ResolvedChars {
+
+    pub fn get(&self, uuid: &'static str) -> Result<Characteristic, MissingCharacteristic> {
+        self.chars
+            .get(uuid)
+            .cloned()
+            .ok_or(MissingCharacteristic { name: char_name(uuid), uuid })
+    }
+
+    pub fn missing(&self) -> Vec<&'static str> {
+        KNOWN_CHARS.iter()
+                   .filter(|(_, uuid)| !self.chars.contains_key(uuid))
+                   .map(|(name, _)| *name)
+                   .collect()
+    }
+}
+
+// (service id, characteristic id) by uuid
+type CharIds = HashMap<&'static str, (u16, u16)>;
+
+// cheap to clone, clones share the cache
+#[derive(Clone, Default)]
+pub struct GattResolver {
+    cache: Arc<Mutex<HashMap<Address, CharIds>>>,
+}
+
+impl GattResolver {
+
+    pub fn shared() -> GattResolver {
+        static SHARED: OnceLock<GattResolver> = OnceLock::new();
+        SHARED.get_or_init(GattResolver::default).clone()
+    }
+
+    // whatever can't be found just isn't in the result, see ResolvedChars::get
+    pub async fn resolve(&self, device: &Device) -> bluer::Result<ResolvedChars> {
+        wait_for_services(device).await?;
+
+        let cached_ids = self.cache
+                             .lock()
+                             .unwrap()
+                             .get(&device.address())
+                             .cloned();
+        if let Some(ids) = cached_ids {
+            match from_cache(device, &ids).await {
+                Ok(Some(resolved)) => {
+                    debug!(device = %device.address(), "resolved characteristics from cache");
+                    return Ok(resolved);
+                },
+                Ok(None) => debug!(device = %device.address(), "cached characteristics are stale"),
+                Err(err) => debug!(device = %device.address(), error = %err, "cached characteristics are gone"),
+            }
+        }
+
+        let (resolved, ids) = walk(device).await?;
+        let missing = resolved.missing();
+        if !missing.is_empty() {
+            warn!(device = %device.address(), ?missing, "volcano is missing characteristics");
+        }
+        self.cache
+            .lock()
+            .unwrap()
+            .insert(device.address(), ids);
+        Ok(resolved)
+    }
+}
+
+async fn wait_for_services(device: &Device) -> bluer::Result<()> {
+    let resolve_until = Instant::now() + SERVICES_RESOLVE_TIMEOUT;
+    while !device.is_services_resolved().await? {
+        if Instant::now() >= resolve_until {
+            // walk whatever is there, it might be enough
+            debug!(device = %device.address(), "services still not resolved");
+            break;
+        }
+        tokio::time::sleep(SERVICES_RESOLVE_POLL).await;
+    }
+    Ok(())
+}
+
+// None if a cached id now points at some other characteristic
+async fn from_cache(device: &Device, ids: &CharIds) -> bluer::Result<Option<ResolvedChars>> {
+    let mut chars = HashMap::new();
+    for (uuid, (service_id, char_id)) in ids {
+        let characteristic = device.service(*service_id)
+                                   .await?
+                                   .characteristic(*char_id)
+                                   .await?;
+        if characteristic.uuid().await?.to_string() != *uuid {
+            return Ok(None);
+        }
+        chars.insert(*uuid, characteristic);
+    }
+    Ok(Some(ResolvedChars { chars }))
+}
+
+async fn walk(device: &Device) -> bluer::Result<(ResolvedChars, CharIds)> {
+    let mut chars = HashMap::new();
+    let mut ids = HashMap::new();
+    for service in device.services().await? {
+        let service_uuid = service.uuid().await?.to_string();
+        if service_uuid != SERVICE1_UUID && service_uuid != SERVICE2_UUID {
+            continue;
+        }
+        for characteristic in service.characteristics().await? {
+            let char_uuid = characteristic.uuid().await?.to_string();
+            let uuid = match KNOWN_CHARS.iter().find(|(_, uuid)| *uuid == char_uuid) {
+                Some((_, uuid)) => *uuid,
+                None => continue,
+            };
+            ids.insert(uuid, (service.id(), characteristic.id()));
+            chars.insert(uuid, characteristic);
+        }
+    }
+    Ok((ResolvedChars { chars }, ids))
+}
+
+fn char_name(uuid: &str) -> &'static str {
+    KNOWN_CHARS.iter()
+               .find(|(_, known_uuid)| *known_uuid == uuid)
+               .map_or("unknown", |(name, _)| *name)
+}
@@ -0,0 +1,96 @@
+use tokio::time::Instant;
+use bluer::gatt::remote::Characteristic;
+use tracing::{instrument, warn};
+
+use crate::{
+    metrics::metrics,
+    utils::HeatingCoolingState,
+    bluetooth_service::tracked_value::TrackedValue,
+};
+
+
+pub struct HeatAirChars {
+    heat_or_air_enabled_char: Characteristic, 
+    start_heat_char: Characteristic,
+    stop_heat_char: Characteristic,
+    start_air_char: Characteristic,
+    stop_air_char: Characteristic,
+    heat_air_state: TrackedValue<HeatingCoolingState>,
+}
+
+// handles reading/writing the heat/air state of the volcano
+impl HeatAirChars {
+
+    pub fn new(heat_or_air_enabled_char: Characteristic, 
+               start_heat_char: Characteristic,
+               stop_heat_char: Characteristic,
+               start_air_char: Characteristic,
+               stop_air_char: Characteristic) -> HeatAirChars {
+        HeatAirChars { 
+            heat_or_air_enabled_char,
+            start_heat_char,
+            stop_heat_char,
+            start_air_char,
+            stop_air_char,
+            heat_air_state: TrackedValue::new("heat_air", HeatingCoolingState::Off),
+        }
+    }
+
+    // this one takes a little while to catch up after a write, the
+    // tracked value keeps reporting the write until the device confirms
+    // it. `None` when the read failed
+    pub async fn read(&mut self) -> Option<HeatingCoolingState> {
+        let read_state = self.get_heat_air_state().await?;
+        Some(self.heat_air_state
+                 .observe_read(read_state, Instant::now()))
+    }
+
+    pub async fn write(&mut self, state: HeatingCoolingState) -> bluer::Result<()> {
+        self.heat_air_state.begin_write(state, Instant::now());
+        let result = self.write_heat_air_state(state).await;
+        if result.is_err() {
+            self.heat_air_state.fail_write();
+        }
+        result
+    }
+
+    #[instrument(level = "debug", name = "ble_read", skip(self), fields(characteristic = "heat_air"))]
+    async fn get_heat_air_state(&self) -> Option<HeatingCoolingState> {
+        let timer = metrics().start_ble_read("heat_air");
+        let heat_air_state = self.heat_or_air_enabled_char
+                                 .read()
+                                 .await
+                                 .map_err(|err| warn!(error = %err, "read failed"))
+                                 .ok()
+                                 .map(|raw_state| {
+                                     HeatingCoolingState::from_device_val(raw_state)
+                                 });
+        timer.observe_duration();
+        if let Some(heat_air_state) = heat_air_state {
+            metrics().set_heat_air_state(heat_air_state);
+        }
+        heat_air_state
+    }
+
+    #[instrument(level = "debug", name = "ble_write", skip(self), fields(characteristic = "heat_air"))]
+    async fn write_heat_air_state(&self,
+                                 state: HeatingCoolingState) -> bluer::Result<()> 
+    {
+        let timer = metrics().start_ble_write("heat_air");
+        let (heat_char, air_char) = match state {
+            HeatingCoolingState::Heating => (&self.start_heat_char, &self.stop_air_char),
+            HeatingCoolingState::Cooling => (&self.start_heat_char, &self.start_air_char),
+            HeatingCoolingState::Off => (&self.stop_heat_char, &self.stop_air_char),
+        };
+        // one gatt op at a time, the link is shared with everything else
+        let heat_result = heat_char.write(&[1]).await;
+        let air_result = air_char.write(&[1]).await;
+        timer.observe_duration();
+        let result = heat_result.and(air_result);
+        match &result {
+            Ok(()) => metrics().set_heat_air_state(state),
+            Err(err) => warn!(error = %err, "write failed"),
+        }
+        result
+    }
+}
@@ -0,0 +1,47 @@
+use std::sync::Mutex;
+use tokio::time::{Duration, Instant};
+
+// the last value read off the device and when we read it. homekit reads
+// every characteristic whenever someone opens the home app, so anything
+// younger than the ttl gets served from here instead of going out over ble
+
+pub struct ReadCache<T> {
+    ttl: Duration,
+    entry: Mutex<Option<(T, Instant)>>,
+}
+
+impl<T: Copy> ReadCache<T> {
+
+    pub fn new(ttl: Duration) -> ReadCache<T> {
+        ReadCache {
+            ttl,
+            entry: Mutex::new(None),
+        }
+    }
+
+    // the cached value, as long as it's younger than the ttl
+    pub fn fresh(&self) -> Option<T> {
+        self.entry
+            .lock()
+            .unwrap()
+            .filter(|(_, read_at)| read_at.elapsed() < self.ttl)
+            .map(|(value, _)| value)
+    }
+
+    // when the cached value was read, fresh or not
+    pub fn read_at(&self) -> Option<Instant> {
+        self.entry
+            .lock()
+            .unwrap()
+            .map(|(_, read_at)| read_at)
+    }
+
+    pub fn store(&self, value: T) {
+        *self.entry.lock().unwrap() = Some((value, Instant::now()));
+    }
+
+    // after a write the cached read says nothing about the device anymore
+    pub fn invalidate(&self) {
+        *self.entry.lock().unwrap() = None;
+    }
+}
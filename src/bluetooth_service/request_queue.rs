@@ -0,0 +1,259 @@
+use std::collections::VecDeque;
+use tokio::sync::oneshot;
+use tracing::debug;
+
+use crate::{
+    bluetooth_service::Message,
+    utils::{HeatingCoolingState, Temperature},
+};
+
+// everything waiting on the ble link. writes come from someone actually
+// pressing something, so they all go out before any read. reads of the same
+// characteristic collapse into one and everyone waiting gets that answer
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Read {
+    CurrTemp,
+    TargTemp,
+    HeatAirState,
+}
+
+pub enum Request {
+    // sets and the disconnect, in the order they came in
+    Write(Message),
+    Read(Read),
+}
+
+#[derive(Default)]
+pub struct RequestQueue {
+    writes: VecDeque<Message>,
+    reads: VecDeque<Read>,
+    curr_temp_waiters: Vec<oneshot::Sender<Temperature>>,
+    targ_temp_waiters: Vec<oneshot::Sender<Temperature>>,
+    heat_air_waiters: Vec<oneshot::Sender<HeatingCoolingState>>,
+}
+
+impl RequestQueue {
+
+    pub fn push(&mut self, message: Message) {
+        match message {
+            Message::GetCurrTemp { resp_tx } => {
+                self.push_read(Read::CurrTemp);
+                self.curr_temp_waiters.push(resp_tx);
+            },
+            Message::GetTargTemp { resp_tx } => {
+                self.push_read(Read::TargTemp);
+                self.targ_temp_waiters.push(resp_tx);
+            },
+            Message::GetHeatAirState { resp_tx } => {
+                self.push_read(Read::HeatAirState);
+                self.heat_air_waiters.push(resp_tx);
+            },
+            message => self.writes.push_back(message),
+        }
+    }
+
+    pub fn pop(&mut self) -> Option<Request> {
+        if let Some(message) = self.writes.pop_front() {
+            return Some(Request::Write(message));
+        }
+        self.reads
+            .pop_front()
+            .map(Request::Read)
+    }
+
+    // answers everyone waiting on this read, including whoever asked
+    // while it was already out over ble
+    pub fn answer_curr_temp(&mut self, curr_temp: Temperature) {
+        self.reads.retain(|read| *read != Read::CurrTemp);
+        answer(&mut self.curr_temp_waiters, curr_temp);
+    }
+
+    pub fn answer_targ_temp(&mut self, targ_temp: Temperature) {
+        self.reads.retain(|read| *read != Read::TargTemp);
+        answer(&mut self.targ_temp_waiters, targ_temp);
+    }
+
+    pub fn answer_heat_air_state(&mut self, heat_air_state: HeatingCoolingState) {
+        self.reads.retain(|read| *read != Read::HeatAirState);
+        answer(&mut self.heat_air_waiters, heat_air_state);
+    }
+
+    // the read can't happen, dropping the waiters' resp_tx tells them
+    pub fn drop_read(&mut self, read: Read) {
+        self.reads.retain(|queued| *queued != read);
+        match read {
+            Read::CurrTemp => self.curr_temp_waiters.clear(),
+            Read::TargTemp => self.targ_temp_waiters.clear(),
+            Read::HeatAirState => self.heat_air_waiters.clear(),
+        }
+    }
+
+    // the link is down: no answer for any read and every write fails, a
+    // disconnect stays queued so the worker still gets to stop
+    pub fn fail_all(&mut self) {
+        self.reads.clear();
+        self.curr_temp_waiters.clear();
+        self.targ_temp_waiters.clear();
+        self.heat_air_waiters.clear();
+        for message in std::mem::take(&mut self.writes) {
+            match message {
+                Message::Disconnect { .. } => self.writes.push_back(message),
+                message => fail_write(message),
+            }
+        }
+    }
+
+    // gatt ops still to do, a deduplicated read counts once
+    pub fn len(&self) -> usize {
+        self.writes.len() + self.reads.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn push_read(&mut self, read: Read) {
+        if self.reads.contains(&read) {
+            debug!(?read, "joining a queued read");
+            return;
+        }
+        self.reads.push_back(read);
+    }
+}
+
+// a write that can't go out because the link is down
+pub fn fail_write(message: Message) {
+    let not_connected = bluer::Error {
+        kind: bluer::ErrorKind::NotReady,
+        message: "the volcano isn't connected".into(),
+    };
+    match message {
+        Message::SetTargTemp { resp_tx, .. } => { let _ = resp_tx.send(Err(not_connected)); },
+        Message::SetHeatAirState { resp_tx, .. } => { let _ = resp_tx.send(Err(not_connected)); },
+        Message::Disconnect { resp_tx } => { let _ = resp_tx.send(Err(not_connected)); },
+        // reads and GetConnected never get this far
+        _ => (),
+    }
+}
+
+fn answer<T: Copy>(waiters: &mut Vec<oneshot::Sender<T>>, value: T) {
+    for resp_tx in waiters.drain(..) {
+        let _ = resp_tx.send(value);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::oneshot::error::TryRecvError;
+
+    fn get_curr_temp() -> (Message, oneshot::Receiver<Temperature>) {
+        let (resp_tx, resp_rx) = oneshot::channel();
+        (Message::GetCurrTemp { resp_tx }, resp_rx)
+    }
+
+    fn get_targ_temp() -> (Message, oneshot::Receiver<Temperature>) {
+        let (resp_tx, resp_rx) = oneshot::channel();
+        (Message::GetTargTemp { resp_tx }, resp_rx)
+    }
+
+    fn set_targ_temp(celsius: f32) -> (Message, oneshot::Receiver<bluer::Result<()>>) {
+        let (resp_tx, resp_rx) = oneshot::channel();
+        (Message::SetTargTemp { temp: Temperature::from_celsius(celsius), resp_tx }, resp_rx)
+    }
+
+    fn set_heat_air_state(state: HeatingCoolingState) -> (Message, oneshot::Receiver<bluer::Result<()>>) {
+        let (resp_tx, resp_rx) = oneshot::channel();
+        (Message::SetHeatAirState { state, resp_tx }, resp_rx)
+    }
+
+    fn disconnect() -> (Message, oneshot::Receiver<bluer::Result<()>>) {
+        let (resp_tx, resp_rx) = oneshot::channel();
+        (Message::Disconnect { resp_tx }, resp_rx)
+    }
+
+    #[test]
+    fn writes_pop_before_reads() {
+        let mut queue = RequestQueue::default();
+        let (read, _curr_rx) = get_curr_temp();
+        queue.push(read);
+        let (write, _set_rx) = set_targ_temp(190.0);
+        queue.push(write);
+        let (read, _targ_rx) = get_targ_temp();
+        queue.push(read);
+        let (write, _state_rx) = set_heat_air_state(HeatingCoolingState::Heating);
+        queue.push(write);
+        assert_eq!(queue.len(), 4);
+
+        assert!(matches!(queue.pop(), Some(Request::Write(Message::SetTargTemp { .. }))));
+        assert!(matches!(queue.pop(), Some(Request::Write(Message::SetHeatAirState { .. }))));
+        assert!(matches!(queue.pop(), Some(Request::Read(Read::CurrTemp))));
+        assert!(matches!(queue.pop(), Some(Request::Read(Read::TargTemp))));
+        assert!(queue.pop().is_none());
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn duplicate_reads_collapse() {
+        let mut queue = RequestQueue::default();
+        let (read, mut first_rx) = get_curr_temp();
+        queue.push(read);
+        let (read, mut second_rx) = get_curr_temp();
+        queue.push(read);
+        assert_eq!(queue.len(), 1);
+
+        assert!(matches!(queue.pop(), Some(Request::Read(Read::CurrTemp))));
+        // asked while the read is already out over ble
+        let (read, mut late_rx) = get_curr_temp();
+        queue.push(read);
+
+        queue.answer_curr_temp(Temperature::from_celsius(185.0));
+        for resp_rx in [&mut first_rx, &mut second_rx, &mut late_rx] {
+            assert_eq!(resp_rx.try_recv(), Ok(Temperature::from_celsius(185.0)));
+        }
+        // the late one got its answer, no second read for it
+        assert!(queue.pop().is_none());
+    }
+
+    #[test]
+    fn drop_read_only_drops_that_read() {
+        let mut queue = RequestQueue::default();
+        let (read, mut curr_rx) = get_curr_temp();
+        queue.push(read);
+        let (read, mut targ_rx) = get_targ_temp();
+        queue.push(read);
+
+        queue.drop_read(Read::CurrTemp);
+        assert_eq!(curr_rx.try_recv(), Err(TryRecvError::Closed));
+        assert_eq!(targ_rx.try_recv(), Err(TryRecvError::Empty));
+
+        assert!(matches!(queue.pop(), Some(Request::Read(Read::TargTemp))));
+        assert!(queue.pop().is_none());
+        queue.answer_targ_temp(Temperature::from_celsius(190.0));
+        assert_eq!(targ_rx.try_recv(), Ok(Temperature::from_celsius(190.0)));
+    }
+
+    #[test]
+    fn fail_all_keeps_the_disconnect() {
+        let mut queue = RequestQueue::default();
+        let (write, mut set_rx) = set_targ_temp(190.0);
+        queue.push(write);
+        let (write, mut disconnect_rx) = disconnect();
+        queue.push(write);
+        let (write, mut state_rx) = set_heat_air_state(HeatingCoolingState::Off);
+        queue.push(write);
+        let (read, mut curr_rx) = get_curr_temp();
+        queue.push(read);
+
+        queue.fail_all();
+        assert!(matches!(set_rx.try_recv(), Ok(Err(_))));
+        assert!(matches!(state_rx.try_recv(), Ok(Err(_))));
+        assert_eq!(curr_rx.try_recv(), Err(TryRecvError::Closed));
+        assert!(matches!(disconnect_rx.try_recv(), Err(TryRecvError::Empty)));
+
+        assert_eq!(queue.len(), 1);
+        assert!(matches!(queue.pop(), Some(Request::Write(Message::Disconnect { .. }))));
+        assert!(queue.pop().is_none());
+    }
+}
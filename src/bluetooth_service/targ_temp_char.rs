@@ -0,0 +1,77 @@
+use tokio::time::Instant;
+use bluer::gatt::remote::Characteristic;
+use tracing::{debug, instrument, warn};
+
+use crate::{
+    metrics::metrics,
+    utils::Temperature,
+    bluetooth_service::tracked_value::TrackedValue,
+};
+
+// handles reading/writing the target temp
+
+pub struct TargTempChar {
+    targ_temp_char: Characteristic,
+    targ_temp: TrackedValue<Temperature>,
+}
+
+impl TargTempChar {
+
+    pub fn new(targ_temp_char: Characteristic) -> TargTempChar {
+        TargTempChar {
+            targ_temp_char,
+            targ_temp: TrackedValue::new("targ_temp", Temperature::zero()),
+        }
+    }
+
+    // `None` when the read failed, nothing made up stands in for it
+    pub async fn read(&mut self) -> Option<Temperature> {
+        let read_temp = self.get_targ_temp().await?;
+        Some(self.targ_temp
+                 .observe_read(read_temp, Instant::now()))
+    }
+
+    pub async fn write(&mut self, temp: Temperature) -> bluer::Result<()> {
+        self.targ_temp.begin_write(temp, Instant::now());
+        let result = self.write_targ_temp(temp)
+                         .await;
+        if result.is_err() {
+            self.targ_temp.fail_write();
+        }
+        result
+    }
+
+    #[instrument(level = "debug", name = "ble_write", skip(self), fields(characteristic = "targ_temp"))]
+    async fn write_targ_temp(&self, temp: Temperature) -> bluer::Result<()> {
+        let device_val = temp.device_val();
+        debug!(?device_val, "writing target temp");
+        let timer = metrics().start_ble_write("targ_temp");
+        let result = self.targ_temp_char
+                         .write(&device_val)
+                         .await;
+        timer.observe_duration();
+        match &result {
+            Ok(()) => metrics().set_targ_temp(temp),
+            Err(err) => warn!(error = %err, "write failed"),
+        }
+        result
+    }
+    
+    #[instrument(level = "debug", name = "ble_read", skip(self), fields(characteristic = "targ_temp"))]
+    async fn get_targ_temp(&self) -> Option<Temperature> {
+        let timer = metrics().start_ble_read("targ_temp");
+        let targ_temp = self.targ_temp_char
+                            .read()
+                            .await
+                            .map_err(|err| warn!(error = %err, "read failed"))
+                            .ok()
+                            .map(|raw_temp| {
+                                Temperature::from_device_val(raw_temp)
+                            });
+        timer.observe_duration();
+        if let Some(targ_temp) = targ_temp {
+            metrics().set_targ_temp(targ_temp);
+        }
+        targ_temp
+    }
+}
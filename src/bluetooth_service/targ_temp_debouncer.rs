@@ -0,0 +1,192 @@
+use std::sync::{Arc, Mutex};
+use tokio::{self, sync};
+use tracing::{debug, warn};
+
+use crate::{
+    bluetooth_service::Message,
+    utils::Temperature,
+};
+
+// dragging the dial in the home app sends a target temp every few ms,
+// so hold on to them until they stop coming and only write the last one
+
+const DEBOUNCE_DURATION: tokio::time::Duration = tokio::time::Duration::from_millis(750);
+
+enum Command {
+    Set(Temperature),
+    // write whatever's pending right away and say how it went
+    Flush(sync::oneshot::Sender<Option<bluer::Result<()>>>),
+}
+
+pub struct TargTempDebouncer {
+    tx: sync::mpsc::UnboundedSender<Command>,
+    pending: Arc<Mutex<Option<Temperature>>>,
+}
+
+impl TargTempDebouncer {
+
+    pub fn new(worker_tx: sync::mpsc::Sender<Message>) -> TargTempDebouncer {
+        let (tx, rx) = sync::mpsc::unbounded_channel();
+        let pending = Arc::new(Mutex::new(None));
+        let run_pending = Arc::clone(&pending);
+        tokio::spawn(async move {
+            Self::run_loop(rx, worker_tx, run_pending).await;
+        });
+        TargTempDebouncer { tx, pending }
+    }
+
+    pub fn set(&self, temp: Temperature) {
+        *self.pending.lock().unwrap() = Some(temp);
+        let _ = self.tx.send(Command::Set(temp));
+    }
+
+    // skips the rest of the wait, `Some(Ok(()))` if nothing was pending,
+    // `None` if the worker is gone
+    pub async fn flush(&self) -> Option<bluer::Result<()>> {
+        let (resp_tx, resp_rx) = sync::oneshot::channel();
+        self.tx.send(Command::Flush(resp_tx)).ok()?;
+        resp_rx.await.ok().flatten()
+    }
+
+    // the value that's waiting to be written, if any, so reads
+    // in the meantime don't snap the dial back to the old value
+    pub fn pending(&self) -> Option<Temperature> {
+        *self.pending.lock().unwrap()
+    }
+
+    async fn run_loop(mut rx: sync::mpsc::UnboundedReceiver<Command>,
+                      worker_tx: sync::mpsc::Sender<Message>,
+                      pending: Arc<Mutex<Option<Temperature>>>) {
+        while let Some(command) = rx.recv().await {
+            let mut temp = match command {
+                Command::Set(temp) => temp,
+                Command::Flush(resp_tx) => {
+                    let _ = resp_tx.send(Some(Ok(())));
+                    continue;
+                },
+            };
+
+            // trailing edge, keep swallowing values until it goes quiet
+            // or someone wants it out now
+            let mut coalesced = 0;
+            let mut flushes = Vec::new();
+            while flushes.is_empty() {
+                match tokio::time::timeout(DEBOUNCE_DURATION, rx.recv()).await {
+                    Ok(Some(Command::Set(newer_temp))) => {
+                        temp = newer_temp;
+                        coalesced += 1;
+                    },
+                    Ok(Some(Command::Flush(resp_tx))) => flushes.push(resp_tx),
+                    _ => break,
+                }
+            }
+            debug!(celsius = temp.celsius(), coalesced, "writing debounced target temp");
+
+            let (resp_tx, resp_rx) = sync::oneshot::channel();
+            let _ = worker_tx.send(Message::SetTargTemp { temp, resp_tx }).await;
+            let result = resp_rx.await.ok();
+            match &result {
+                Some(Ok(())) => (),
+                Some(Err(err)) => warn!(error = %err, "debounced target temp write failed"),
+                None => warn!("worker dropped the debounced target temp write"),
+            }
+
+            // something newer may have come in while we were writing
+            {
+                let mut pending_temp = pending.lock().unwrap();
+                if *pending_temp == Some(temp) {
+                    *pending_temp = None;
+                }
+            }
+
+            for resp_tx in flushes {
+                let _ = resp_tx.send(result.clone());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::time::{Duration, Instant};
+
+    fn temp(celsius: f32) -> Temperature {
+        Temperature::from_celsius(celsius)
+    }
+
+    // stands in for the worker, hands back the next target temp write
+    async fn next_write(worker_rx: &mut sync::mpsc::Receiver<Message>)
+                        -> (Temperature, sync::oneshot::Sender<bluer::Result<()>>) {
+        match worker_rx.recv().await {
+            Some(Message::SetTargTemp { temp, resp_tx }) => (temp, resp_tx),
+            other => panic!("expected a target temp write, got {:?}", other),
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn coalesces_into_one_write() {
+        let (worker_tx, mut worker_rx) = sync::mpsc::channel(8);
+        let debouncer = TargTempDebouncer::new(worker_tx);
+        let start = Instant::now();
+        for celsius in [180.0, 185.0, 190.0] {
+            debouncer.set(temp(celsius));
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+
+        let (written, resp_tx) = next_write(&mut worker_rx).await;
+        assert_eq!(written, temp(190.0));
+        // the wait starts over with every new value
+        assert!(start.elapsed() >= Duration::from_millis(200) + DEBOUNCE_DURATION);
+        resp_tx.send(Ok(())).unwrap();
+
+        // nothing left, this only comes back once the write above is done
+        assert!(matches!(debouncer.flush().await, Some(Ok(()))));
+        assert_eq!(debouncer.pending(), None);
+        assert!(worker_rx.try_recv().is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn flush_skips_the_wait() {
+        let (worker_tx, mut worker_rx) = sync::mpsc::channel(8);
+        let debouncer = Arc::new(TargTempDebouncer::new(worker_tx));
+        let start = Instant::now();
+        debouncer.set(temp(185.0));
+        let flushing = tokio::spawn({
+            let debouncer = Arc::clone(&debouncer);
+            async move { debouncer.flush().await }
+        });
+
+        let (written, resp_tx) = next_write(&mut worker_rx).await;
+        assert_eq!(written, temp(185.0));
+        assert!(start.elapsed() < DEBOUNCE_DURATION);
+        resp_tx.send(Err(bluer::Error {
+            kind: bluer::ErrorKind::NotReady,
+            message: "the volcano isn't connected".into(),
+        })).unwrap();
+
+        // the flush gets the write's own result
+        assert!(matches!(flushing.await.unwrap(), Some(Err(_))));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn pending_keeps_a_newer_temp() {
+        let (worker_tx, mut worker_rx) = sync::mpsc::channel(8);
+        let debouncer = TargTempDebouncer::new(worker_tx);
+        debouncer.set(temp(185.0));
+        assert_eq!(debouncer.pending(), Some(temp(185.0)));
+
+        let (_, resp_tx) = next_write(&mut worker_rx).await;
+        // the dial moved again while 185 was going out
+        debouncer.set(temp(190.0));
+        resp_tx.send(Ok(())).unwrap();
+
+        let (written, resp_tx) = next_write(&mut worker_rx).await;
+        assert_eq!(written, temp(190.0));
+        assert_eq!(debouncer.pending(), Some(temp(190.0)));
+        resp_tx.send(Ok(())).unwrap();
+
+        assert!(matches!(debouncer.flush().await, Some(Ok(()))));
+        assert_eq!(debouncer.pending(), None);
+    }
+}
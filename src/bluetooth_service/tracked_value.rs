@@ -0,0 +1,219 @@
+use std::fmt::Debug;
+use tokio::time::{Duration, Instant};
+use tracing::{debug, info, warn};
+
+use crate::utils::ApproxEq;
+
+// a device value that both homekit (through us) and the volcano's own
+// panel can change. last writer wins: a write from us is the newest thing
+// until the device either reads back the same value (confirmed), shows
+// some third value (the panel was pressed after us, the device wins), or
+// the ack window runs out (the write got lost, whatever we reported in
+// the meantime was a phantom)
+
+// the volcano can take a few polls to reflect a write, heat especially
+const ACK_TIMEOUT: Duration = Duration::from_secs(6);
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Source {
+    Device,
+    Remote,
+}
+
+#[derive(Debug, Copy, Clone)]
+struct PendingWrite<T> {
+    value: T,
+    prev_value: T,
+    written_at: Instant,
+}
+
+pub struct TrackedValue<T> {
+    name: &'static str,
+    value: T,
+    source: Source,
+    changed_at: Instant,
+    pending: Option<PendingWrite<T>>,
+}
+
+impl<T: ApproxEq + Copy + Debug> TrackedValue<T> {
+
+    pub fn new(name: &'static str, value: T) -> TrackedValue<T> {
+        TrackedValue {
+            name,
+            value,
+            source: Source::Device,
+            changed_at: Instant::now(),
+            pending: None,
+        }
+    }
+
+    // what we should report right now, an unconfirmed write counts
+    pub fn value(&self) -> T {
+        self.pending
+            .map_or(self.value, |pending| pending.value)
+    }
+
+    pub fn begin_write(&mut self, value: T, now: Instant) {
+        if let Some(pending) = self.pending {
+            debug!(name = self.name,
+                   superseded = ?pending.value,
+                   "replacing an unconfirmed write");
+        }
+        self.pending = Some(PendingWrite {
+            value,
+            prev_value: self.value,
+            written_at: now,
+        });
+    }
+
+    // the write errored out, so the device never saw it
+    pub fn fail_write(&mut self) {
+        if let Some(pending) = self.pending.take() {
+            debug!(name = self.name, written = ?pending.value, "dropping failed write");
+        }
+    }
+
+    // feed in a fresh read off the device, returns what to report
+    pub fn observe_read(&mut self, read: T, now: Instant) -> T {
+        let pending = match self.pending.take() {
+            Some(pending) => pending,
+            None => {
+                self.update(read, Source::Device, now);
+                return read;
+            },
+        };
+        let since_write = now.duration_since(pending.written_at);
+
+        if read.approx_eq(&pending.value) {
+            debug!(name = self.name, ?since_write, "write confirmed by readback");
+            self.update(read, Source::Remote, now);
+            return read;
+        }
+
+        if !read.approx_eq(&pending.prev_value) {
+            info!(name = self.name,
+                  written = ?pending.value,
+                  device = ?read,
+                  ?since_write,
+                  "changed on the device after our write, device wins, our write is lost");
+            self.update(read, Source::Device, now);
+            return read;
+        }
+
+        if since_write < ACK_TIMEOUT {
+            // device hasn't caught up yet, keep reporting the write
+            self.pending = Some(pending);
+            return pending.value;
+        }
+
+        warn!(name = self.name,
+              written = ?pending.value,
+              device = ?read,
+              ?since_write,
+              "phantom write, it never showed up on the device, dropping it");
+        self.update(read, Source::Device, now);
+        read
+    }
+
+    fn update(&mut self, value: T, source: Source, now: Instant) {
+        if !value.approx_eq(&self.value) {
+            if source == Source::Device {
+                info!(name = self.name,
+                      from = ?self.value,
+                      to = ?value,
+                      last_change_by = ?self.source,
+                      since_last_change = ?now.duration_since(self.changed_at),
+                      "changed on the device");
+            }
+            self.changed_at = now;
+        }
+        self.value = value;
+        self.source = source;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::Temperature;
+
+    fn temp(cel_val: f32) -> Temperature {
+        Temperature::from_celsius(cel_val)
+    }
+
+    fn secs(secs: u64) -> Duration {
+        Duration::from_secs(secs)
+    }
+
+    // the device last read 180
+    fn tracked_at_180(now: Instant) -> TrackedValue<Temperature> {
+        let mut tracked = TrackedValue::new("targ_temp", Temperature::zero());
+        tracked.observe_read(temp(180.0), now);
+        tracked
+    }
+
+    #[test]
+    fn write_confirmed_by_readback() {
+        let now = Instant::now();
+        let mut tracked = tracked_at_180(now);
+        tracked.begin_write(temp(190.0), now);
+
+        // the device hasn't caught up yet, keep reporting the write
+        let reported = tracked.observe_read(temp(180.0), now + secs(1));
+        assert!(reported.approx_eq(&temp(190.0)));
+        assert!(tracked.pending.is_some());
+
+        // close enough counts, the device works in tenths
+        let reported = tracked.observe_read(temp(190.01), now + secs(2));
+        assert!(reported.approx_eq(&temp(190.0)));
+        assert!(tracked.pending.is_none());
+        assert_eq!(tracked.source, Source::Remote);
+    }
+
+    #[test]
+    fn panel_press_after_write_wins() {
+        let now = Instant::now();
+        let mut tracked = tracked_at_180(now);
+        tracked.begin_write(temp(190.0), now);
+
+        // someone turned the dial on the panel before our write showed up
+        let reported = tracked.observe_read(temp(200.0), now + secs(1));
+        assert_eq!(reported, temp(200.0));
+        assert_eq!(tracked.value(), temp(200.0));
+        assert!(tracked.pending.is_none());
+        assert_eq!(tracked.source, Source::Device);
+    }
+
+    #[test]
+    fn unacked_write_is_dropped_after_timeout() {
+        let now = Instant::now();
+        let mut tracked = tracked_at_180(now);
+        tracked.begin_write(temp(190.0), now);
+
+        let reported = tracked.observe_read(temp(180.0), now + ACK_TIMEOUT - secs(1));
+        assert_eq!(reported, temp(190.0));
+
+        let reported = tracked.observe_read(temp(180.0), now + ACK_TIMEOUT + secs(1));
+        assert_eq!(reported, temp(180.0));
+        assert_eq!(tracked.value(), temp(180.0));
+        assert!(tracked.pending.is_none());
+        assert_eq!(tracked.source, Source::Device);
+    }
+
+    #[test]
+    fn failed_write_is_forgotten() {
+        let now = Instant::now();
+        let mut tracked = tracked_at_180(now);
+        tracked.begin_write(temp(190.0), now);
+        assert_eq!(tracked.value(), temp(190.0));
+
+        tracked.fail_write();
+        assert_eq!(tracked.value(), temp(180.0));
+        assert!(tracked.pending.is_none());
+
+        // and the next read isn't mistaken for a panel press against it
+        let reported = tracked.observe_read(temp(180.0), now + secs(1));
+        assert_eq!(reported, temp(180.0));
+        assert_eq!(tracked.source, Source::Device);
+    }
+}
@@ -1,187 +1,347 @@
-use tokio::{self, sync};
+use std::{future::Future, pin::Pin};
+use tokio::{self, sync, time::{Duration, Instant}};
+use tokio_stream::{Stream, StreamExt};
 use bluer::{
-    gatt::remote::Characteristic,
-    Device
+    Address,
+    Device,
+    SessionEvent,
 };
+use tracing::{info, instrument, warn};
 
 use crate::{
     Result,
+    metrics::metrics,
     bluetooth_service::{
-        heat_air_worker::HeatAirStateWorker,
-        targ_temp_worker::TargTempWorker,
-        curr_temp_worker::CurrTempWorker,
+        discovery::{self, DeviceSelector},
+        gatt_resolver::{
+            GattResolver,
+            MissingCharacteristic,
+            ResolvedChars,
+            CURR_TEMP_CHAR_UUID,
+            TARG_TEMP_CHAR_UUID,
+            IS_HEATORAIR_ENABLED_CHAR_UUID,
+            START_HEAT_CHAR_UUID,
+            STOP_HEAT_CHAR_UUID,
+            START_AIR_CHAR_UUID,
+            STOP_AIR_CHAR_UUID,
+        },
+        heat_air_chars::HeatAirChars,
+        targ_temp_char::TargTempChar,
+        curr_temp_char::CurrTempChar,
+        request_queue::{self, Read, Request, RequestQueue},
         Message,
     },
 };
 
-const SERVICE1_UUID: &str = "10100000-5354-4f52-5a26-4249434b454c";
-const SERVICE2_UUID: &str = "10110000-5354-4f52-5a26-4249434b454c";
-const FIRMWARE_CHAR_UUID: &str = "10100005-5354-4f52-5a26-4249434b454c";
-const SERIAL_CHAR_UUID: &str = "10100008-5354-4f52-5a26-4249434b454c";
-const MODEL_CHAR_UUID: &str = "10100007-5354-4f52-5a26-4249434b454c";
-const CURR_TEMP_CHAR_UUID: &str = "10110001-5354-4f52-5a26-4249434b454c";
-const TARG_TEMP_CHAR_UUID: &str = "10110003-5354-4f52-5a26-4249434b454c";
-const IS_HEATORAIR_ENABLED_CHAR_UUID: &str = "1010000c-5354-4f52-5a26-4249434b454c";
-const START_HEAT_CHAR_UUID: &str = "1011000f-5354-4f52-5a26-4249434b454c";
-const STOP_HEAT_CHAR_UUID: &str = "10110010-5354-4f52-5a26-4249434b454c";
-const START_AIR_CHAR_UUID: &str = "10110013-5354-4f52-5a26-4249434b454c";
-const STOP_AIR_CHAR_UUID: &str = "10110014-5354-4f52-5a26-4249434b454c";
-
-#[allow(dead_code)]
+// how long to look for the volcano again after the adapter came back,
+// requests wait on this, so it can't be open ended
+const REACQUIRE_SCAN_TIMEOUT: Duration = Duration::from_secs(10);
+
+// between reacquire attempts that requests kick off, so a full queue isn't
+// a scan per request. an adapter showing up tries right away regardless
+const REACQUIRE_BACKOFF_MIN: Duration = Duration::from_secs(5);
+const REACQUIRE_BACKOFF_MAX: Duration = Duration::from_secs(60);
+
+// for a whole connect, retries included. bluez can sit on a single
+// attempt at a volcano that's off for ~20s, and everything queued waits
+// behind it
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+// between finding attempts, the service answers for itself meanwhile
+const FIND_RETRY_DELAY: Duration = Duration::from_secs(10);
+
+// owns the connection and every characteristic on it, so there's only ever
+// one gatt op on the link at a time. messages get pulled off the channel
+// into a RequestQueue as soon as they show up, the queue decides what goes next
+
 pub struct Worker {
     volcano: Device,
-    firmware_char: Characteristic,
-    model_char: Characteristic,
-    serial_char: Characteristic,
-    rx: sync::mpsc::Receiver<Message>,
-    curr_temp_tx: sync::mpsc::Sender<Message>,
-    targ_temp_tx: sync::mpsc::Sender<Message>,
-    heat_air_state_tx: sync::mpsc::Sender<Message>,
+    resolver: GattResolver,
+    chars: Chars,
+    queue: RequestQueue,
+    // the adapter is pinned by address, its name can change on a replug
+    adapter_address: Address,
+    adapter_events: Pin<Box<dyn Stream<Item = SessionEvent> + Send>>,
+    needs_reacquire: bool,
+    next_reacquire: Instant,
+    reacquire_backoff: Duration,
+}
+
+// everything we hold on the volcano's gatt services, all of it goes
+// stale once the adapter or bluetoothd goes away. a firmware that lacks
+// a characteristic still gets the rest, reads of what's missing (and
+// reads that fail) get no answer and writes to it fail
+struct Chars {
+    curr_temp: std::result::Result<CurrTempChar, MissingCharacteristic>,
+    targ_temp: std::result::Result<TargTempChar, MissingCharacteristic>,
+    heat_air: std::result::Result<HeatAirChars, MissingCharacteristic>,
 }
 
 impl Worker {
-    pub async fn run_loop(&mut self) {
-        while let Some(message) = self.rx.recv().await {
-            Self::connect_to_volcano_if_needed(&self.volcano)
-                .await
-                .expect("can't connect to the volcano");
+    pub async fn run_loop(&mut self, rx: &mut sync::mpsc::Receiver<Message>) {
+        loop {
+            // only block once there's nothing left to do
+            if self.queue.is_empty() {
+                tokio::select! {
+                    message = rx.recv() => match message {
+                        Some(message) => self.enqueue(message).await,
+                        None => return,
+                    },
+                    Some(evt) = self.adapter_events.next() => {
+                        self.on_session_event(evt).await;
+                        continue;
+                    },
+                }
+            }
+            self.drain_rx(rx).await;
+            let request = match self.queue.pop() {
+                Some(request) => request,
+                None => continue,
+            };
+            self.report_queue_depth();
 
-            match message {
-                Message::GetCurrTemp { resp_tx } => {
-                    let _ = self.curr_temp_tx
-                                .send(Message::GetCurrTemp { resp_tx })
-                                .await;
-                },
-                Message::GetTargTemp { resp_tx } => {
-                    let _ = self.targ_temp_tx
-                                .send(Message::GetTargTemp { resp_tx })
-                                .await;
+            if let Request::Write(Message::Disconnect { resp_tx }) = request {
+                let success = self.disconnect_from_volcano_if_needed()
+                                  .await;
+                metrics().set_connected(false);
+                let _ = resp_tx.send(success);
+                info!(device = %self.volcano.address(), "closing run loop");
+                return;
+            }
+
+            // everything else queued would only try connecting again, back
+            // to back, so it all fails together
+            if !self.ensure_connected().await {
+                self.fail_request(request);
+                self.queue.fail_all();
+                self.report_queue_depth();
+                continue;
+            }
+
+            match request {
+                Request::Read(Read::CurrTemp) => {
+                    let curr_temp = match &mut self.chars.curr_temp {
+                        Ok(curr_temp) => curr_temp.read().await,
+                        Err(_) => None,
+                    };
+                    self.drain_rx(rx).await;
+                    match curr_temp {
+                        Some(curr_temp) => self.queue.answer_curr_temp(curr_temp),
+                        None => self.queue.drop_read(Read::CurrTemp),
+                    }
                 },
-                Message::SetTargTemp { temp, resp_tx } => {
-                    let _ = self.targ_temp_tx
-                                .send(Message::SetTargTemp { temp, resp_tx })
-                                .await;
+                Request::Read(Read::TargTemp) => {
+                    let targ_temp = match &mut self.chars.targ_temp {
+                        Ok(targ_temp) => targ_temp.read().await,
+                        Err(_) => None,
+                    };
+                    self.drain_rx(rx).await;
+                    match targ_temp {
+                        Some(targ_temp) => self.queue.answer_targ_temp(targ_temp),
+                        None => self.queue.drop_read(Read::TargTemp),
+                    }
                 },
-                Message::GetHeatAirState { resp_tx } => {
-                    let _ = self.heat_air_state_tx
-                                .send(Message::GetHeatAirState { resp_tx })
-                                .await;
+                Request::Read(Read::HeatAirState) => {
+                    let heat_air_state = match &mut self.chars.heat_air {
+                        Ok(heat_air) => heat_air.read().await,
+                        Err(_) => None,
+                    };
+                    self.drain_rx(rx).await;
+                    match heat_air_state {
+                        Some(heat_air_state) => self.queue.answer_heat_air_state(heat_air_state),
+                        None => self.queue.drop_read(Read::HeatAirState),
+                    }
                 },
-                Message::SetHeatAirState { state, resp_tx } => {
-                    let _ = self.heat_air_state_tx
-                                .send(Message::SetHeatAirState { state, resp_tx })
-                                .await;
+                Request::Write(Message::SetTargTemp { temp, resp_tx }) => {
+                    let success = match &mut self.chars.targ_temp {
+                        Ok(targ_temp) => targ_temp.write(temp).await,
+                        Err(missing) => Err(missing.clone().into()),
+                    };
+                    let _ = resp_tx.send(success);
                 },
-                Message::Disconnect { resp_tx } => {
-                    let success = self.disconnect_from_volcano_if_needed()
-                                      .await;
+                Request::Write(Message::SetHeatAirState { state, resp_tx }) => {
+                    let success = match &mut self.chars.heat_air {
+                        Ok(heat_air) => heat_air.write(state).await,
+                        Err(missing) => Err(missing.clone().into()),
+                    };
                     let _ = resp_tx.send(success);
-                    println!("closing run loop");
-                    return;
                 },
+                Request::Write(_) => (),
             }
+            self.report_queue_depth();
         }
     }
 
-    pub async fn new(volcano: Device,
-                 rx: sync::mpsc::Receiver<Message>) -> Result<Worker> {
-        let mut firmware_char: Option<Characteristic> = None;
-        let mut model_char: Option<Characteristic> = None;
-        let mut serial_char: Option<Characteristic> = None;
-        let mut curr_temp_char: Option<Characteristic> = None;
-        let mut targ_temp_char: Option<Characteristic> = None;
-        let mut heat_or_air_enabled_char: Option<Characteristic> = None;
-        let mut start_heat_char: Option<Characteristic> = None;
-        let mut stop_heat_char: Option<Characteristic> = None;
-        let mut start_air_char: Option<Characteristic> = None;
-        let mut stop_air_char: Option<Characteristic> = None;
-
-        // make the initial connection and wait a little for it to settle
-        Worker::connect_to_volcano_if_needed(&volcano).await?;
-        tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
-
-        // discover the characteristics we care about
-        for service in volcano.services().await? {
-            let service_uuid_string = service.uuid().await?.to_string();
-            if !service_uuid_string.eq(&SERVICE1_UUID) 
-                && !service_uuid_string.eq(&SERVICE2_UUID) {
-                continue
-            }
-            for characterictic in service.characteristics().await? {
-                let char_uuid_string = characterictic.uuid().await?.to_string();
-                match char_uuid_string.as_str() {
-                    FIRMWARE_CHAR_UUID => firmware_char = Some(characterictic),
-                    MODEL_CHAR_UUID => model_char = Some(characterictic),
-                    SERIAL_CHAR_UUID => serial_char = Some(characterictic),
-                    CURR_TEMP_CHAR_UUID => curr_temp_char = Some(characterictic),
-                    TARG_TEMP_CHAR_UUID => targ_temp_char = Some(characterictic),
-                    IS_HEATORAIR_ENABLED_CHAR_UUID => heat_or_air_enabled_char = Some(characterictic),
-                    START_HEAT_CHAR_UUID => start_heat_char = Some(characterictic),
-                    STOP_HEAT_CHAR_UUID => stop_heat_char = Some(characterictic),
-                    START_AIR_CHAR_UUID => start_air_char = Some(characterictic),
-                    STOP_AIR_CHAR_UUID => stop_air_char = Some(characterictic),
-                    _ => (),
-                };
-            }
+    async fn enqueue(&mut self, message: Message) {
+        // answer without forcing a connection, that's the whole point of asking
+        if let Message::GetConnected { resp_tx } = message {
+            let is_connected = self.volcano
+                                   .is_connected()
+                                   .await
+                                   .unwrap_or(false);
+            let _ = resp_tx.send(is_connected);
+            return;
         }
-        
-        // spin up the targ temp worker
-        let (targ_temp_tx, targ_temp_rx) = sync::mpsc::channel(32);
-        let mut targ_temp_worker = TargTempWorker::new(targ_temp_char.unwrap(),
-                                                       targ_temp_rx);
-        tokio::spawn(async move {
-            targ_temp_worker.run_loop().await;
-        });
+        self.queue.push(message);
+    }
+
+    async fn drain_rx(&mut self, rx: &mut sync::mpsc::Receiver<Message>) {
+        while let Ok(message) = rx.try_recv() {
+            self.enqueue(message).await;
+        }
+    }
+
+    fn report_queue_depth(&self) {
+        metrics().set_queue_depth("connection", self.queue.len());
+    }
+
+    // readers get no answer, the dropped resp_tx turns into a None for
+    // them, writers get an error
+    fn fail_request(&mut self, request: Request) {
+        match request {
+            Request::Read(read) => self.queue.drop_read(read),
+            Request::Write(message) => request_queue::fail_write(message),
+        }
+    }
+
+    // false if the request can't go out right now
+    async fn ensure_connected(&mut self) -> bool {
+        if self.needs_reacquire
+            && (Instant::now() < self.next_reacquire || !self.try_reacquire().await) {
+            return false;
+        }
+        let connected = Self::connect_to_volcano_if_needed(&self.volcano)
+                            .await
+                            .map_err(|err| warn!(error = %err, "can't connect to the volcano"));
+        match connected {
+            Ok(did_reconnect) => {
+                if did_reconnect {
+                    metrics().inc_reconnects();
+                }
+                true
+            },
+            Err(()) => {
+                // the device object itself being gone means bluez lost it,
+                // e.g. bluetoothd restarted without us seeing the adapter go
+                self.needs_reacquire = self.volcano.is_connected().await.is_err();
+                false
+            },
+        }
+    }
+
+    async fn on_session_event(&mut self, evt: SessionEvent) {
+        match evt {
+            SessionEvent::AdapterRemoved(name) if name == self.volcano.adapter_name() => {
+                warn!(adapter = %name, "bluetooth adapter went away");
+                metrics().set_connected(false);
+                self.needs_reacquire = true;
+            },
+            SessionEvent::AdapterAdded(name) if self.needs_reacquire => {
+                // might not even be ours, reacquire goes by address
+                info!(adapter = %name, "bluetooth adapter showed up");
+                self.try_reacquire().await;
+            },
+            _ => (),
+        }
+    }
+
+    // a failed attempt pushes the next one out, doubling each time
+    async fn try_reacquire(&mut self) -> bool {
+        let reacquired = self.reacquire()
+                             .await
+                             .map_err(|err| warn!(error = %err,
+                                                  retry_in = ?self.reacquire_backoff,
+                                                  "couldn't get the volcano back yet"));
+        match reacquired {
+            Ok(()) => {
+                self.reacquire_backoff = REACQUIRE_BACKOFF_MIN;
+                true
+            },
+            Err(()) => {
+                self.next_reacquire = Instant::now() + self.reacquire_backoff;
+                self.reacquire_backoff = (self.reacquire_backoff * 2).min(REACQUIRE_BACKOFF_MAX);
+                false
+            },
+        }
+    }
+
+    // the adapter came back (replugged, bluetoothd restarted), everything
+    // we held on it is stale, so find the volcano again and redo the gatt lookup
+    async fn reacquire(&mut self) -> Result<()> {
+        let address = self.volcano.address();
+        let selector = DeviceSelector {
+            adapter: Some(self.adapter_address.to_string()),
+            address: Some(address),
+            ..Default::default()
+        };
+        let known_volcano = discovery::known_volcano(selector.adapter.as_deref(), address).await?;
+        let volcano = match known_volcano {
+            Some(volcano) => volcano,
+            None => tokio::time::timeout(REACQUIRE_SCAN_TIMEOUT,
+                                         discovery::discover_volcano(&selector))
+                                         .await??
+                                         .ok_or("the volcano didn't show up again")?,
+        };
+        Self::connect_to_volcano_if_needed(&volcano).await?;
+        self.chars = Chars::new(&self.resolver.resolve(&volcano).await?);
+        self.volcano = volcano;
+        self.needs_reacquire = false;
+        metrics().inc_reconnects();
+        info!(device = %address, adapter = %self.volcano.adapter_name(), "got the volcano back");
+        Ok(())
+    }
+
+    pub async fn new(volcano: Device) -> Result<Worker> {
+        let session = bluer::Session::new().await?;
+        let adapter_address = session.adapter(volcano.adapter_name())?
+                                     .address()
+                                     .await?;
+        let adapter_events = Box::pin(session.events().await?);
+
+        Worker::connect_to_volcano_if_needed(&volcano).await?;
+        let resolver = GattResolver::shared();
+        let chars = Chars::new(&resolver.resolve(&volcano).await?);
 
-        // heat air state reader worker
-        let (heat_air_tx, heat_air_rx) = sync::mpsc::channel(32);
-        let mut heat_air_worker = HeatAirStateWorker::new(
-                                    heat_or_air_enabled_char.unwrap(),
-                                    start_heat_char.unwrap(),
-                                    stop_heat_char.unwrap(),
-                                    start_air_char.unwrap(),
-                                    stop_air_char.unwrap(),
-                                    heat_air_rx);
-        tokio::spawn(async move {
-            heat_air_worker.run_loop().await;
-        });
-        
-        // and also the curr temp worker
-        let (curr_temp_tx, curr_temp_rx) = sync::mpsc::channel(32);
-        let mut curr_temp_worker = CurrTempWorker::new(curr_temp_char.unwrap(),
-                                                       curr_temp_rx);
-        tokio::spawn(async move {
-            curr_temp_worker.run_loop().await;
-        });
-        
         Ok(Worker {
             volcano,
-            firmware_char: firmware_char.unwrap(),
-            model_char: model_char.unwrap(),
-            serial_char: serial_char.unwrap(),
-            rx,
-            curr_temp_tx,
-            targ_temp_tx,
-            heat_air_state_tx: heat_air_tx,
+            resolver,
+            chars,
+            queue: RequestQueue::default(),
+            adapter_address,
+            adapter_events,
+            needs_reacquire: false,
+            next_reacquire: Instant::now(),
+            reacquire_backoff: REACQUIRE_BACKOFF_MIN,
         })
     }
 
-    async fn connect_to_volcano_if_needed(volcano: &Device) -> Result<()> {
+    // returns whether a new connection had to be made
+    #[instrument(level = "debug", name = "ble_connect", skip(volcano), fields(device = %volcano.address()))]
+    async fn connect_to_volcano_if_needed(volcano: &Device) -> Result<bool> {
         if !volcano.is_connected().await? {
-            let mut retries = 2;
-            loop {
-                match volcano.connect().await {
-                    Ok(()) => break,
-                    Err(err) if retries > 0 => {
-                        println!("Connect error: {}", &err);
-                        retries -= 1;
-                    }
-                    Err(err) => return Err(Box::new(err)),
+            metrics().set_connected(false);
+            tokio::time::timeout(CONNECT_TIMEOUT, Self::connect_with_retries(volcano))
+                .await
+                .map_err(|_| "timed out connecting to the volcano")??;
+            metrics().set_connected(true);
+            return Ok(true);
+        }
+        metrics().set_connected(true);
+        Ok(false)
+    }
+
+    async fn connect_with_retries(volcano: &Device) -> bluer::Result<()> {
+        let mut retries = 2;
+        loop {
+            match volcano.connect().await {
+                Ok(()) => return Ok(()),
+                Err(err) if retries > 0 => {
+                    warn!(error = %err, "connect failed, retrying");
+                    retries -= 1;
                 }
+                Err(err) => return Err(err),
             }
-            return Ok(());
         }
-        Ok(())
     }
 
     async fn disconnect_from_volcano_if_needed(&self) -> bluer::Result<()> {
@@ -191,3 +351,81 @@ impl Worker {
         self.volcano.disconnect().await
     }
 }
+
+impl Chars {
+
+    fn new(resolved: &ResolvedChars) -> Chars {
+        Chars {
+            curr_temp: resolved.get(CURR_TEMP_CHAR_UUID)
+                               .map(CurrTempChar::new),
+            targ_temp: resolved.get(TARG_TEMP_CHAR_UUID)
+                               .map(TargTempChar::new),
+            heat_air: Self::heat_air(resolved),
+        }
+    }
+
+    fn heat_air(resolved: &ResolvedChars) -> std::result::Result<HeatAirChars, MissingCharacteristic> {
+        Ok(HeatAirChars::new(resolved.get(IS_HEATORAIR_ENABLED_CHAR_UUID)?,
+                             resolved.get(START_HEAT_CHAR_UUID)?,
+                             resolved.get(STOP_HEAT_CHAR_UUID)?,
+                             resolved.get(START_AIR_CHAR_UUID)?,
+                             resolved.get(STOP_AIR_CHAR_UUID)?))
+    }
+}
+
+// stands in for the worker until `find` turns up a volcano and it's
+// connected, then hands the channel over. until then nobody waits: it's
+// not connected, reads get no answer and writes fail
+pub async fn find_and_run<F, Fut>(mut find: F, mut rx: sync::mpsc::Receiver<Message>)
+where F: FnMut() -> Fut + Send,
+      Fut: Future<Output = Result<Device>> + Send {
+    let mut worker = loop {
+        let mut attempt = Box::pin(async {
+            let volcano = find().await?;
+            Worker::new(volcano).await
+        });
+        let found = loop {
+            tokio::select! {
+                found = &mut attempt => break found.map_err(|err| {
+                    warn!(error = %err, "couldn't get to the volcano, retrying");
+                }),
+                message = rx.recv() => match message {
+                    Some(message) => if !answer_unavailable(message) { return },
+                    None => return,
+                },
+            }
+        };
+        if let Ok(worker) = found {
+            break worker;
+        }
+
+        let retry = tokio::time::sleep(FIND_RETRY_DELAY);
+        tokio::pin!(retry);
+        loop {
+            tokio::select! {
+                _ = &mut retry => break,
+                message = rx.recv() => match message {
+                    Some(message) => if !answer_unavailable(message) { return },
+                    None => return,
+                },
+            }
+        }
+    };
+    worker.run_loop(&mut rx).await;
+}
+
+// false once we've been told to stop
+fn answer_unavailable(message: Message) -> bool {
+    match message {
+        Message::GetConnected { resp_tx } => {
+            let _ = resp_tx.send(false);
+            true
+        },
+        Message::Disconnect { resp_tx } => {
+            let _ = resp_tx.send(Ok(()));
+            false
+        },
+        // dropping the resp_tx hands the caller a None
+        _ => true,
+    }
+}
@@ -0,0 +1,223 @@
+use std::net::SocketAddr;
+use bluer::Address;
+use clap::{Args, Parser, Subcommand, ValueEnum};
+
+use crate::bluetooth_service::discovery::DeviceSelector;
+
+
+/// homekit bridge for the storz & bickel volcano
+#[derive(Parser, Debug)]
+#[command(name = "pele", version)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    /// log filter, either a level or per module directives like "info,pele=debug,hap=warn"
+    #[arg(long, env = "PELE_LOG", default_value = "info", global = true)]
+    pub log: String,
+
+    /// defaults to journald when run by systemd, text otherwise
+    #[arg(long, value_enum, env = "PELE_LOG_FORMAT", global = true)]
+    pub log_format: Option<LogFormat>,
+
+    #[command(flatten)]
+    pub bridge: BridgeArgs,
+}
+
+// options for running the bridge itself, i.e. no subcommand
+#[derive(Args, Debug)]
+pub struct BridgeArgs {
+    /// serve prometheus metrics, the json api and the live event stream on
+    /// this address, e.g. 0.0.0.0:9091
+    #[arg(long, env = "PELE_HTTP_ADDR")]
+    pub http_addr: Option<SocketAddr>,
+
+    /// how long a value read off the volcano is served from cache before
+    /// homekit or the api reading it again goes out over bluetooth
+    #[arg(long, env = "PELE_READ_TTL_MS", default_value_t = 1500)]
+    pub read_ttl_ms: u64,
+
+    /// turn the heat and air off when pele is stopped
+    #[arg(long, env = "PELE_OFF_ON_EXIT")]
+    pub off_on_exit: bool,
+
+    /// what the volcano does when a controller asks it to identify
+    /// itself, e.g. while adding it in the home app
+    #[arg(long, value_enum, env = "PELE_IDENTIFY", default_value_t = IdentifyMode::Nudge)]
+    pub identify: IdentifyMode,
+
+    #[command(flatten)]
+    pub device: DeviceArgs,
+
+    #[command(flatten)]
+    pub poll: PollArgs,
+
+    #[command(flatten)]
+    pub mqtt: MqttArgs,
+}
+
+// which volcano to use when there's more than one in range, whichever
+// gets picked is remembered and reused on the next start
+#[derive(Args, Debug, Clone)]
+pub struct DeviceArgs {
+    /// bluetooth adapter to use, by name (hci1) or address, instead of
+    /// the default one
+    #[arg(long, env = "PELE_ADAPTER")]
+    pub adapter: Option<String>,
+
+    /// bluetooth address of the volcano to use, e.g. F4:12:FA:12:34:56
+    #[arg(long, env = "PELE_DEVICE_ADDRESS")]
+    pub device_address: Option<Address>,
+
+    /// serial number of the volcano to use, checking it means connecting
+    /// to every volcano in range
+    #[arg(long, env = "PELE_DEVICE_SERIAL")]
+    pub device_serial: Option<String>,
+
+    /// scan for a few seconds and take the strongest signal instead of
+    /// the first volcano that shows up
+    #[arg(long, env = "PELE_PREFER_STRONGEST")]
+    pub prefer_strongest: bool,
+}
+
+impl DeviceArgs {
+
+    pub fn selector(&self) -> DeviceSelector {
+        DeviceSelector {
+            adapter: self.adapter.clone(),
+            address: self.device_address,
+            serial: self.device_serial.clone(),
+            strongest_rssi: self.prefer_strongest,
+        }
+    }
+}
+
+// how often the volcano gets polled in the background when nothing else
+// has read it, all in milliseconds
+#[derive(Args, Debug, Clone)]
+pub struct PollArgs {
+    /// while the heat or the air pump is on
+    #[arg(long, env = "PELE_POLL_ACTIVE_MS", default_value_t = 1000)]
+    pub poll_active_ms: u64,
+
+    /// while everything is off
+    #[arg(long, env = "PELE_POLL_IDLE_MS", default_value_t = 10000)]
+    pub poll_idle_ms: u64,
+
+    /// first retry once the volcano is gone, doubles from there
+    #[arg(long, env = "PELE_POLL_DISCONNECTED_MS", default_value_t = 5000)]
+    pub poll_disconnected_ms: u64,
+
+    /// the most the disconnected backoff grows to
+    #[arg(long, env = "PELE_POLL_DISCONNECTED_MAX_MS", default_value_t = 20000)]
+    pub poll_disconnected_max_ms: u64,
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct MqttArgs {
+    /// bridge the volcano onto this mqtt broker, with home assistant discovery
+    #[arg(long, env = "PELE_MQTT_HOST")]
+    pub mqtt_host: Option<String>,
+
+    #[arg(long, env = "PELE_MQTT_PORT", default_value_t = 1883)]
+    pub mqtt_port: u16,
+
+    #[arg(long, env = "PELE_MQTT_USER")]
+    pub mqtt_user: Option<String>,
+
+    #[arg(long, env = "PELE_MQTT_PASSWORD", hide_env_values = true)]
+    pub mqtt_password: Option<String>,
+
+    /// prefix for pele's own state and command topics
+    #[arg(long, env = "PELE_MQTT_TOPIC", default_value = "pele")]
+    pub mqtt_topic: String,
+
+    #[arg(long, env = "PELE_MQTT_DISCOVERY_PREFIX", default_value = "homeassistant")]
+    pub mqtt_discovery_prefix: String,
+
+    /// client id on the broker, defaults to one made from the homekit
+    /// device id so two pele instances don't keep kicking each other off
+    #[arg(long, env = "PELE_MQTT_CLIENT_ID")]
+    pub mqtt_client_id: Option<String>,
+}
+
+#[derive(ValueEnum, Copy, Clone, Debug, PartialEq)]
+pub enum IdentifyMode {
+    /// bump the target temp a degree and back
+    Nudge,
+    /// run the air pump for a few seconds, or stop it if it's running.
+    /// nudges instead while the heat is off
+    Air,
+    /// do nothing
+    Off,
+}
+
+#[derive(ValueEnum, Copy, Clone, Debug, PartialEq)]
+pub enum LogFormat {
+    Text,
+    Json,
+    Journald,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// list and summarize past sessions
+    History(HistoryArgs),
+    /// look for volcanos in range and print what they say about themselves
+    Scan(ScanArgs),
+    /// see and revoke the apple devices paired with pele
+    Pairings(PairingsArgs),
+}
+
+#[derive(Args, Debug)]
+pub struct HistoryArgs {
+    /// how to print the sessions, csv and json are meant for exporting
+    #[arg(long, value_enum, default_value_t = HistoryFormat::Table)]
+    pub format: HistoryFormat,
+
+    /// only show the most recent n sessions
+    #[arg(long)]
+    pub limit: Option<usize>,
+}
+
+#[derive(ValueEnum, Copy, Clone, Debug, PartialEq)]
+pub enum HistoryFormat {
+    Table,
+    Csv,
+    Json,
+}
+
+#[derive(Args, Debug)]
+pub struct ScanArgs {
+    /// bluetooth adapter to scan on, by name (hci1) or address
+    #[arg(long, env = "PELE_ADAPTER")]
+    pub adapter: Option<String>,
+
+    /// how long to scan for
+    #[arg(long, default_value_t = 10)]
+    pub seconds: u64,
+
+    /// only list what the scan itself sees, skip connecting to
+    /// each volcano for its model, serial and firmware
+    #[arg(long)]
+    pub no_connect: bool,
+}
+
+#[derive(Args, Debug)]
+pub struct PairingsArgs {
+    #[command(subcommand)]
+    pub command: PairingsCommand,
+}
+
+// these work on the stored pairings, restart a running pele afterwards
+#[derive(Subcommand, Debug)]
+pub enum PairingsCommand {
+    /// list the paired controllers
+    List,
+    /// unpair one controller, by the id `list` shows
+    Remove {
+        id: String,
+    },
+    /// unpair everything, pele can be set up from scratch afterwards
+    Reset,
+}
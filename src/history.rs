@@ -0,0 +1,107 @@
+use std::collections::HashMap;
+use hap::serde_json;
+
+use crate::{
+    cli::{HistoryArgs, HistoryFormat},
+    session::{store::SessionStore, SessionRecord},
+    Result,
+};
+
+// backs `pele history`
+
+pub async fn print_history(args: HistoryArgs) -> Result<()> {
+    let mut records = SessionStore::current_dir()?.load_all().await?;
+    if let Some(limit) = args.limit {
+        let skip = records.len().saturating_sub(limit);
+        records.drain(..skip);
+    }
+
+    match args.format {
+        HistoryFormat::Table => print_table(&records),
+        HistoryFormat::Csv => print_csv(&records),
+        HistoryFormat::Json => println!("{}", serde_json::to_string_pretty(&records)?),
+    }
+    Ok(())
+}
+
+fn print_table(records: &[SessionRecord]) {
+    if records.is_empty() {
+        println!("no sessions yet");
+        return;
+    }
+
+    println!("{:<18} {:>9}  {:<20} {:>8} {:>8}",
+             "start", "length", "temps (°C)", "to temp", "air");
+    for record in records {
+        println!("{:<18} {:>9}  {:<20} {:>8} {:>8}",
+                 record.start.format("%Y-%m-%d %H:%M"),
+                 format_secs(record.duration_secs()),
+                 format_temps(&record.targ_temps_c, ", "),
+                 record.time_to_temp_secs.map_or("-".into(), format_secs),
+                 format_secs(record.air_secs));
+    }
+
+    let total_secs: i64 = records.iter().map(SessionRecord::duration_secs).sum();
+    let air_secs: i64 = records.iter().map(|record| record.air_secs).sum();
+    let times_to_temp: Vec<i64> = records.iter()
+                                         .filter_map(|record| record.time_to_temp_secs)
+                                         .collect();
+
+    println!();
+    println!("sessions:         {}", records.len());
+    println!("total heat time:  {}", format_secs(total_secs));
+    println!("total air time:   {}", format_secs(air_secs));
+    if !times_to_temp.is_empty() {
+        let avg = times_to_temp.iter().sum::<i64>() / times_to_temp.len() as i64;
+        println!("avg time to temp: {}", format_secs(avg));
+    }
+    if let Some(temp) = favourite_temp(records) {
+        println!("favourite temp:   {:.1}°C", temp);
+    }
+}
+
+fn print_csv(records: &[SessionRecord]) {
+    println!("start,end,end_reason,duration_secs,targ_temps_c,max_temp_c,time_to_temp_secs,air_secs");
+    for record in records {
+        println!("{},{},{},{},{},{},{},{}",
+                 record.start.to_rfc3339(),
+                 record.end.to_rfc3339(),
+                 serde_json::to_value(record.end_reason)
+                            .ok()
+                            .and_then(|val| val.as_str().map(String::from))
+                            .unwrap_or_default(),
+                 record.duration_secs(),
+                 format_temps(&record.targ_temps_c, ";"),
+                 record.max_temp_c.map_or(String::new(), |temp| format!("{:.1}", temp)),
+                 record.time_to_temp_secs.map_or(String::new(), |secs| secs.to_string()),
+                 record.air_secs);
+    }
+}
+
+fn format_secs(secs: i64) -> String {
+    let (hours, mins, secs) = (secs / 3600, (secs % 3600) / 60, secs % 60);
+    if hours > 0 {
+        return format!("{}h{:02}m", hours, mins);
+    }
+    format!("{}m{:02}s", mins, secs)
+}
+
+fn format_temps(temps: &[f32], sep: &str) -> String {
+    temps.iter()
+         .map(|temp| format!("{:.1}", temp))
+         .collect::<Vec<_>>()
+         .join(sep)
+}
+
+// the target temp with the most sessions using it
+fn favourite_temp(records: &[SessionRecord]) -> Option<f32> {
+    let mut counts: HashMap<i32, usize> = HashMap::new();
+    for record in records {
+        for temp in &record.targ_temps_c {
+            *counts.entry((temp * 10.0).round() as i32).or_default() += 1;
+        }
+    }
+    counts.into_iter()
+          .max_by_key(|(temp, count)| (*count, *temp))
+          .map(|(temp, _)| temp as f32 / 10.0)
+}
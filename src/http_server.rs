@@ -0,0 +1,69 @@
+use std::{net::SocketAddr, sync::Arc};
+use axum::{
+    extract::FromRef,
+    http::{header, StatusCode},
+    response::IntoResponse,
+    routing::{get, put},
+    Router,
+};
+use tokio::sync;
+use tracing::info;
+
+use crate::{
+    bluetooth_service::BluetoothService,
+    metrics::metrics,
+    utils::DeviceState,
+    Result,
+};
+
+mod api;
+mod events;
+
+// optional http server living next to the hap server
+
+#[derive(Clone)]
+struct AppState {
+    service: Arc<BluetoothService>,
+    state_rx: sync::watch::Receiver<DeviceState>,
+}
+
+impl FromRef<AppState> for Arc<BluetoothService> {
+
+    fn from_ref(app_state: &AppState) -> Arc<BluetoothService> {
+        Arc::clone(&app_state.service)
+    }
+}
+
+impl FromRef<AppState> for sync::watch::Receiver<DeviceState> {
+
+    fn from_ref(app_state: &AppState) -> sync::watch::Receiver<DeviceState> {
+        app_state.state_rx.clone()
+    }
+}
+
+pub async fn serve(addr: SocketAddr, service: Arc<BluetoothService>) -> Result<()> {
+    let state_rx = service.subscribe();
+    let app = Router::new().route("/metrics", get(get_metrics))
+                           .route("/status", get(api::get_status))
+                           .route("/events", get(events::get_events))
+                           .route("/target-temperature", put(api::put_target_temperature))
+                           .route("/heat", put(api::put_heat))
+                           .route("/air", put(api::put_air))
+                           .with_state(AppState { service, state_rx });
+
+    info!(%addr, "serving http");
+    axum::Server::try_bind(&addr)?
+                .serve(app.into_make_service())
+                .await?;
+    Ok(())
+}
+
+async fn get_metrics() -> impl IntoResponse {
+    match metrics().render() {
+        Ok(body) => (StatusCode::OK,
+                     [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+                     body).into_response(),
+        Err(err) => (StatusCode::INTERNAL_SERVER_ERROR,
+                     err.to_string()).into_response(),
+    }
+}
@@ -0,0 +1,91 @@
+use std::sync::Arc;
+use chrono::{DateTime, Utc};
+use axum::{
+    extract::State,
+    http::StatusCode,
+    response::IntoResponse,
+    Json,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    bluetooth_service::BluetoothService,
+    utils::{DeviceState, HeatingCoolingState, Temperature},
+};
+
+// json api for clients that don't speak homekit
+
+#[derive(Serialize)]
+pub struct Status {
+    connected: bool,
+    // set while the values are left over from the last run, to when
+    // they were read, nothing's been read off the volcano since
+    stale_since: Option<DateTime<Utc>>,
+    current_temperature: Option<f32>,
+    target_temperature: Option<f32>,
+    heat: Option<bool>,
+    air: Option<bool>,
+}
+
+impl From<DeviceState> for Status {
+
+    fn from(state: DeviceState) -> Status {
+        Status {
+            connected: state.connected,
+            stale_since: state.stale_since.map(DateTime::from),
+            current_temperature: state.curr_temp.map(|temp| temp.celsius()),
+            target_temperature: state.targ_temp.map(|temp| temp.celsius()),
+            heat: state.heat_air_state.map(|state| state.is_heat_on()),
+            air: state.heat_air_state.map(|state| state.is_air_on()),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct TargetTemperatureBody {
+    celsius: f32,
+}
+
+#[derive(Deserialize)]
+pub struct OnOffBody {
+    on: bool,
+}
+
+// straight from the service's snapshot, no ble round trip
+pub async fn get_status(State(service): State<Arc<BluetoothService>>) -> Json<Status> {
+    Json(Status::from(service.state()))
+}
+
+pub async fn put_target_temperature(State(service): State<Arc<BluetoothService>>,
+                                    Json(body): Json<TargetTemperatureBody>) -> impl IntoResponse {
+    let temp = Temperature::from_celsius(body.celsius);
+    if !temp.is_settable() {
+        return (StatusCode::UNPROCESSABLE_ENTITY,
+                "target temperature is out of the volcano's range").into_response();
+    }
+    write_result(service.write_temp(temp).await)
+}
+
+pub async fn put_heat(State(service): State<Arc<BluetoothService>>,
+                      Json(body): Json<OnOffBody>) -> impl IntoResponse {
+    let curr_state = service.get_curr_heat_air_state().await;
+    let state = HeatingCoolingState::with_heat(body.on, curr_state);
+    write_result(service.set_curr_heat_air_state(state).await)
+}
+
+pub async fn put_air(State(service): State<Arc<BluetoothService>>,
+                     Json(body): Json<OnOffBody>) -> impl IntoResponse {
+    let curr_state = service.get_curr_heat_air_state().await;
+    let state = HeatingCoolingState::with_air(body.on, curr_state);
+    write_result(service.set_curr_heat_air_state(state).await)
+}
+
+fn write_result(result: Option<bluer::Result<()>>) -> axum::response::Response {
+    match result {
+        Some(Ok(())) => StatusCode::NO_CONTENT.into_response(),
+        Some(Err(err)) => (StatusCode::BAD_GATEWAY,
+                           format!("the volcano didn't take the write: {}", err)).into_response(),
+        None => (StatusCode::BAD_GATEWAY,
+                 "couldn't reach the volcano").into_response(),
+    }
+}
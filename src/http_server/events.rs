@@ -0,0 +1,25 @@
+use axum::{
+    extract::State,
+    response::sse::{Event, KeepAlive, Sse},
+};
+use hap::futures::{Stream, StreamExt};
+use tokio::sync;
+use tokio_stream::wrappers::WatchStream;
+
+use crate::{
+    http_server::api::Status,
+    utils::DeviceState,
+};
+
+// server-sent events, one "state" event with the full status
+// on connect and then again every time something changes
+
+pub async fn get_events(State(state_rx): State<sync::watch::Receiver<DeviceState>>)
+    -> Sse<impl Stream<Item = Result<Event, axum::Error>>>
+{
+    let stream = WatchStream::new(state_rx).map(|state| {
+        Event::default().event("state")
+                        .json_data(Status::from(state))
+    });
+    Sse::new(stream).keep_alive(KeepAlive::default())
+}
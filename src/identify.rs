@@ -0,0 +1,97 @@
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+use tokio::time::Duration;
+use tracing::{debug, info, warn};
+
+use crate::{
+    bluetooth_service::BluetoothService,
+    cli::IdentifyMode,
+    utils::{ApproxEq, HeatingCoolingState, Temperature},
+};
+
+// homekit's identify, makes the volcano do something visible for a few
+// seconds with the same writes homekit itself would send, then puts
+// things back the way they were unless someone changed them meanwhile
+
+const IDENTIFY_DURATION: Duration = Duration::from_secs(3);
+const NUDGE_CELSIUS: f32 = 1.0;
+
+pub struct Identifier {
+    service: Arc<BluetoothService>,
+    mode: IdentifyMode,
+    is_running: AtomicBool,
+}
+
+impl Identifier {
+
+    pub fn new(service: Arc<BluetoothService>, mode: IdentifyMode) -> Identifier {
+        Identifier {
+            service,
+            mode,
+            is_running: AtomicBool::new(false),
+        }
+    }
+
+    pub async fn run(&self) {
+        if self.mode == IdentifyMode::Off {
+            return;
+        }
+        // controllers like to send a couple in a row
+        if self.is_running.swap(true, Ordering::SeqCst) {
+            debug!("already identifying");
+            return;
+        }
+        info!(mode = ?self.mode, "identifying");
+
+        let heat_air_state = self.service.get_curr_heat_air_state().await;
+        match (self.mode, heat_air_state) {
+            // pulsing air with the heat off would turn the heat on
+            (IdentifyMode::Air, Some(state)) if state.is_heat_on() => self.pulse_air(state).await,
+            _ => self.nudge_targ_temp().await,
+        }
+        self.is_running.store(false, Ordering::SeqCst);
+    }
+
+    // the pump is loud enough to pick the right one out
+    async fn pulse_air(&self, state: HeatingCoolingState) {
+        let pulsed = HeatingCoolingState::with_air(!state.is_air_on(), Some(state));
+        if !matches!(self.service.set_curr_heat_air_state(pulsed).await, Some(Ok(()))) {
+            warn!("couldn't identify, the volcano didn't take the write");
+            return;
+        }
+        tokio::time::sleep(IDENTIFY_DURATION).await;
+
+        let curr_state = self.service.get_curr_heat_air_state().await;
+        if curr_state.map_or(true, |curr_state| curr_state.approx_eq(&pulsed)) {
+            let _ = self.service.set_curr_heat_air_state(state).await;
+        }
+    }
+
+    // a degree up (or down at the top of the range) and back, the
+    // display shows the new target both times
+    async fn nudge_targ_temp(&self) {
+        let targ_temp = match self.service.get_targ_temp().await {
+            Some(targ_temp) => targ_temp,
+            None => {
+                warn!("couldn't identify, the volcano isn't connected");
+                return;
+            },
+        };
+        let mut nudged = Temperature::from_celsius(targ_temp.celsius() + NUDGE_CELSIUS);
+        if !nudged.is_settable() {
+            nudged = Temperature::from_celsius(targ_temp.celsius() - NUDGE_CELSIUS);
+        }
+        if !matches!(self.service.write_temp(nudged).await, Some(Ok(()))) {
+            warn!("couldn't identify, the volcano didn't take the write");
+            return;
+        }
+        tokio::time::sleep(IDENTIFY_DURATION).await;
+
+        let curr_temp = self.service.get_targ_temp().await;
+        if curr_temp.map_or(true, |curr_temp| curr_temp.approx_eq(&nudged)) {
+            let _ = self.service.write_temp(targ_temp).await;
+        }
+    }
+}
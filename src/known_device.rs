@@ -0,0 +1,83 @@
+use bluer::{Address, Device};
+use hap::{
+    serde_json,
+    storage::{FileStorage, Storage},
+};
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::{
+    bluetooth_service::discovery::{self, DeviceSelector},
+    Result,
+};
+
+// the volcano we settled on last time, kept next to hap's own data so a
+// restart goes straight back to it instead of scanning (and maybe picking
+// the neighbour's)
+
+const KNOWN_DEVICE_KEY: &str = "volcano.json";
+
+#[derive(Serialize, Deserialize)]
+struct KnownDevice {
+    address: String,
+    // only if it was picked by serial
+    serial: Option<String>,
+}
+
+impl KnownDevice {
+
+    // asking for a different address or serial means picking another one
+    fn fits(&self, address: Address, selector: &DeviceSelector) -> bool {
+        selector.address.map_or(true, |wanted| wanted == address)
+            && selector.serial.as_ref().map_or(true, |wanted| self.serial.as_ref() == Some(wanted))
+    }
+}
+
+pub async fn find_volcano(storage: &mut FileStorage,
+                          selector: &DeviceSelector) -> Result<Device> {
+    if let Some(address) = load(storage, selector).await {
+        match discovery::known_volcano(selector.adapter.as_deref(), address).await? {
+            Some(device) => {
+                info!(device = %address, "using the volcano from last time");
+                return Ok(device);
+            },
+            None => warn!(device = %address, "bluez forgot the volcano from last time, scanning"),
+        }
+    }
+
+    let device = discovery::discover_volcano(selector)
+                           .await?
+                           .ok_or("couldn't find the volcano")?;
+    info!(device = %device.address(),
+          name = ?device.name().await.ok().flatten(),
+          "found volcano");
+
+    let known_device = KnownDevice {
+        address: device.address().to_string(),
+        serial: selector.serial.clone(),
+    };
+    if let Err(err) = save(storage, &known_device).await {
+        warn!(error = %err, "couldn't remember the volcano");
+    }
+    Ok(device)
+}
+
+async fn load(storage: &FileStorage, selector: &DeviceSelector) -> Option<Address> {
+    let bytes = storage.load_bytes(KNOWN_DEVICE_KEY).await.ok()?;
+    let known_device: KnownDevice = match serde_json::from_slice(&bytes) {
+        Ok(known_device) => known_device,
+        Err(err) => {
+            warn!(error = %err, "ignoring unreadable remembered volcano");
+            return None;
+        },
+    };
+    let address = known_device.address.parse().ok()?;
+    known_device.fits(address, selector)
+                .then_some(address)
+}
+
+async fn save(storage: &mut FileStorage, known_device: &KnownDevice) -> Result<()> {
+    let bytes = serde_json::to_vec(known_device)?;
+    storage.save_bytes(KNOWN_DEVICE_KEY, &bytes).await?;
+    Ok(())
+}
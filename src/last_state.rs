@@ -0,0 +1,113 @@
+use chrono::{DateTime, Utc};
+use hap::{
+    serde_json,
+    storage::{FileStorage, Storage},
+};
+use serde::{Deserialize, Serialize};
+use tokio::{sync, time::{Duration, Instant}};
+use tracing::{info, warn};
+
+use crate::{
+    utils::{DeviceState, HeatingCoolingState, Temperature},
+    Result,
+};
+
+// what the volcano last showed, kept next to hap's own data so a restart
+// starts from there (marked stale) instead of 0°C and off, which homekit
+// automations would happily act on
+
+const LAST_STATE_KEY: &str = "last_state.json";
+
+// the current temp moves every poll while heating, no need to hit the
+// disk for each one. heat/air and target changes are saved right away,
+// whatever's left goes out on shutdown
+const CURR_TEMP_SAVE_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Serialize, Deserialize)]
+struct LastState {
+    saved_at: DateTime<Utc>,
+    curr_temp_celsius: Option<f32>,
+    targ_temp_celsius: Option<f32>,
+    // homekit's value, 0 off, 1 heat, 2 heat and air
+    heat_air_state: Option<u8>,
+}
+
+impl LastState {
+
+    fn new(state: &DeviceState) -> LastState {
+        LastState {
+            saved_at: Utc::now(),
+            curr_temp_celsius: state.curr_temp.map(|temp| temp.celsius()),
+            targ_temp_celsius: state.targ_temp.map(|temp| temp.celsius()),
+            heat_air_state: state.heat_air_state.map(|state| state.homekit_val()),
+        }
+    }
+
+    fn device_state(&self) -> DeviceState {
+        DeviceState {
+            curr_temp: self.curr_temp_celsius.map(Temperature::from_celsius),
+            targ_temp: self.targ_temp_celsius.map(Temperature::from_celsius),
+            heat_air_state: self.heat_air_state.map(HeatingCoolingState::from_homekit_val),
+            stale_since: Some(self.saved_at.into()),
+            ..Default::default()
+        }
+    }
+}
+
+// DeviceState::default() if there's nothing (usable) saved
+pub async fn load(storage: &FileStorage) -> DeviceState {
+    let bytes = match storage.load_bytes(LAST_STATE_KEY).await {
+        Ok(bytes) => bytes,
+        Err(_) => return DeviceState::default(),
+    };
+    match serde_json::from_slice::<LastState>(&bytes) {
+        Ok(last_state) => {
+            info!(saved_at = %last_state.saved_at, "starting from the last known state");
+            last_state.device_state()
+        },
+        Err(err) => {
+            warn!(error = %err, "ignoring unreadable last known state");
+            DeviceState::default()
+        },
+    }
+}
+
+// saves whatever gets read off the volcano until shutdown. values that
+// are still stale never get saved, they'd come back with a new saved_at
+pub async fn save_changes(mut storage: FileStorage,
+                          mut state_rx: sync::watch::Receiver<DeviceState>,
+                          mut shutdown_rx: sync::watch::Receiver<bool>) {
+    // nothing from this run is on disk yet
+    let mut saved = DeviceState::default();
+    let mut saved_at = Instant::now();
+    loop {
+        let is_stopping = tokio::select! {
+            changed = state_rx.changed() => changed.is_err(),
+            _ = shutdown_rx.changed() => true,
+        };
+        let state = *state_rx.borrow_and_update();
+
+        let is_curr_temp_due = is_stopping
+                               || saved_at.elapsed() >= CURR_TEMP_SAVE_INTERVAL;
+        let is_due = state.stale_since.is_none()
+                     && (state.targ_temp != saved.targ_temp
+                         || state.heat_air_state != saved.heat_air_state
+                         || (state.curr_temp != saved.curr_temp && is_curr_temp_due));
+        if is_due {
+            if let Err(err) = save(&mut storage, &LastState::new(&state)).await {
+                warn!(error = %err, "couldn't save the last known state");
+            }
+            saved = state;
+            saved_at = Instant::now();
+        }
+        if is_stopping {
+            break;
+        }
+    }
+}
+
+async fn save(storage: &mut FileStorage, last_state: &LastState) -> Result<()> {
+    let bytes = serde_json::to_vec(last_state)?;
+    storage.save_bytes(LAST_STATE_KEY, &bytes).await?;
+    Ok(())
+}
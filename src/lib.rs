@@ -0,0 +1,35 @@
+//! Talks to a Storz & Bickel Volcano Hybrid over bluetooth.
+//!
+//! [`BluetoothService`] finds a volcano, keeps the connection to it up and
+//! exposes its temperatures and heat/air state as plain async calls. The
+//! pele HomeKit bridge is built on top of this, other tools can be too.
+//!
+//! ```no_run
+//! use std::time::Duration;
+//! use pele::{BluetoothService, DeviceSelector, Temperature};
+//!
+//! # async fn run() -> pele::Result<()> {
+//! let volcano = BluetoothService::new(&DeviceSelector::default(),
+//!                                     Duration::from_secs(1)).await?;
+//! volcano.write_temp(Temperature::from_celsius(185.0)).await;
+//!
+//! // something has to poll for changes made on the device itself to show up
+//! let mut state_rx = volcano.subscribe();
+//! volcano.poll().await;
+//! println!("{:?}", *state_rx.borrow_and_update());
+//! # Ok(())
+//! # }
+//! ```
+
+use std::error::Error;
+
+pub mod bluetooth_service;
+pub mod metrics;
+pub mod utils;
+
+pub use crate::{
+    bluetooth_service::{discovery::DeviceSelector, BluetoothService},
+    utils::{DeviceState, HeatingCoolingState, Temperature},
+};
+
+pub type Result<T> = std::result::Result<T, Box<dyn Error>>;
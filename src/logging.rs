@@ -0,0 +1,43 @@
+use std::io;
+use tracing_subscriber::{fmt, prelude::*, EnvFilter};
+
+use crate::{
+    cli::LogFormat,
+    Result,
+};
+
+// logs go to stderr so `pele history --format csv > out.csv` stays clean.
+// journald gets the structured fields as-is (DEVICE=, CHARACTERISTIC=, ERROR=...)
+pub fn init(filter: &str, format: Option<LogFormat>) -> Result<()> {
+    let filter = EnvFilter::try_new(filter)?;
+
+    let is_under_systemd = std::env::var_os("JOURNAL_STREAM").is_some();
+    let journald_layer = match format {
+        Some(LogFormat::Journald) => Some(tracing_journald::layer()?),
+        None if is_under_systemd => tracing_journald::layer().ok(),
+        _ => None,
+    }.map(|layer| layer.with_field_prefix(None));
+
+    let format = match (format, &journald_layer) {
+        (Some(format), _) => format,
+        (None, Some(_)) => LogFormat::Journald,
+        (None, None) => LogFormat::Text,
+    };
+    let text_layer = (format == LogFormat::Text).then(|| {
+        fmt::layer().with_writer(io::stderr)
+    });
+    let json_layer = (format == LogFormat::Json).then(|| {
+        fmt::layer().json()
+                    .with_current_span(true)
+                    .with_span_list(true)
+                    .with_writer(io::stderr)
+    });
+
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(journald_layer)
+        .with(text_layer)
+        .with(json_layer)
+        .try_init()?;
+    Ok(())
+}
@@ -1,42 +1,146 @@
-use tokio;
-use std::{error::Error, sync::Arc};
+use tokio::{self, sync};
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+use clap::Parser;
+use tracing::{info, warn};
 use hap::{
     server::{IpServer, Server},
-    storage::FileStorage,
+    storage::{FileStorage, Storage},
 };
 
-mod bluetooth_service;
-mod utils;
+mod cli;
+mod history;
+mod http_server;
+mod identify;
+mod known_device;
+mod last_state;
+mod logging;
+mod mqtt;
+mod pairings;
+mod poll_rate;
+mod scan;
+mod session;
+mod setup_code;
+mod shutdown;
+mod systemd;
 mod volcano_factory;
 
-pub type Result<T> = std::result::Result<T, Box<dyn Error>>;
+// the bridge is just one consumer of the library, bringing these in at the
+// root lets its modules keep reaching them as crate::utils and friends
+use pele::{bluetooth_service, metrics, utils, Result};
 
-use crate::bluetooth_service::BluetoothService;
+use crate::{
+    bluetooth_service::BluetoothService,
+    cli::{BridgeArgs, Cli, Command},
+    poll_rate::PollRate,
+    session::{store::SessionStore, SessionRecorder},
+};
 
 
 #[tokio::main]
-async fn main() -> hap::Result<()> {
-    let service = Arc::new(BluetoothService::new()
-                                .await
-                                .expect("shit's fucked yo!"));
-    let volcano = volcano_factory::create_volcano(Arc::clone(&service)).unwrap();
+async fn main() -> Result<()> {
+    let cli = Cli::parse();
+    logging::init(&cli.log, cli.log_format)?;
+    match cli.command {
+        Some(Command::History(args)) => history::print_history(args).await,
+        Some(Command::Scan(args)) => scan::print_scan(args).await,
+        Some(Command::Pairings(args)) => pairings::manage(args).await,
+        None => run_bridge(cli.bridge).await,
+    }
+}
+
+async fn run_bridge(args: BridgeArgs) -> Result<()> {
+    // homekit comes up right away, the volcano gets found and connected
+    // in the background and the accessory goes live once it answers.
+    // until then it shows what the volcano showed last time
     let mut storage = FileStorage::current_dir().await?;
+    let last_known = last_state::load(&storage).await;
+    let selector = args.device.selector();
+    let read_ttl = Duration::from_millis(args.read_ttl_ms);
+    let service = Arc::new(BluetoothService::start(move || {
+        let selector = selector.clone();
+        async move {
+            let mut storage = FileStorage::current_dir().await?;
+            known_device::find_volcano(&mut storage, &selector).await
+        }
+    }, read_ttl, last_known));
+    let volcano = volcano_factory::create_volcano(Arc::clone(&service),
+                                                  &last_known,
+                                                  args.identify)
+                                 .await
+                                 .unwrap();
+
     let config = volcano_factory::get_volcano_config_from_storage(&mut storage)
                                  .await.unwrap();
+    let hap_addr = SocketAddr::new(config.host, config.port);
+    let device_id = config.device_id;
+    if storage.list_pairings().await?.is_empty() {
+        let setup_id = setup_code::setup_id(&mut storage).await?;
+        setup_code::print(&config, &setup_id)?;
+    }
     let server = IpServer::new(config, storage).await?;
     let volcano = server.add_accessory(volcano).await?;
 
-    let background_service = Arc::clone(&service);
+    let sessions = SessionRecorder::new(SessionStore::current_dir()?);
+    let poll_rate = PollRate::new(&args.poll);
+    let (shutdown_tx, shutdown_rx) = sync::watch::channel(false);
+    let poll_service = Arc::clone(&service);
+    let poll_shutdown_rx = shutdown_rx.clone();
+    let watchdog_shutdown_rx = shutdown_rx.clone();
+    let update_service = Arc::clone(&service);
+    let last_state_storage = FileStorage::current_dir().await?;
+    let last_state_rx = service.subscribe();
+    let last_state_shutdown_rx = shutdown_rx.clone();
+    // shutdown waits on all of these, so the last state gets flushed too
+    let update_loop = tokio::spawn(async move {
+        tokio::join!(poll_rate::poll_loop(poll_service, poll_rate, poll_shutdown_rx),
+                     volcano_factory::char_update_loop(update_service,
+                                                       volcano,
+                                                       sessions,
+                                                       shutdown_rx),
+                     last_state::save_changes(last_state_storage,
+                                              last_state_rx,
+                                              last_state_shutdown_rx));
+    });
+
+    if let Some(http_addr) = args.http_addr {
+        let http_service = Arc::clone(&service);
+        tokio::spawn(async move {
+            if let Err(err) = http_server::serve(http_addr, http_service).await {
+                warn!(error = %err, "http server stopped");
+            }
+        });
+    }
+
+    if args.mqtt.mqtt_host.is_some() {
+        let mqtt_service = Arc::clone(&service);
+        tokio::spawn(async move {
+            mqtt::run(args.mqtt, mqtt_service, device_id).await;
+        });
+    }
+
+    let ready_service = Arc::clone(&service);
+    tokio::spawn(async move {
+        systemd::notify_when_ready(hap_addr, ready_service).await;
+    });
+
+    let watchdog_service = Arc::clone(&service);
     tokio::spawn(async move {
-        volcano_factory::char_update_loop(background_service, volcano).await;
+        systemd::watchdog_loop(watchdog_service, watchdog_shutdown_rx).await;
     });
 
     let handle = server.run_handle();
-//    std::env::set_var("RUST_LOG", "hap=debug");
-//    env_logger::init();
 
-    let _ = handle.await;
-    let _ = service.disconnect().await;
-    println!("goodbye!");
+    tokio::select! {
+        result = handle => {
+            if let Err(err) = result {
+                warn!(error = %err, "hap server stopped");
+            }
+        },
+        signal = shutdown::wait_for_signal() => info!(signal, "shutting down"),
+    }
+
+    systemd::notify_stopping();
+    shutdown::shut_down(service, update_loop, shutdown_tx, args.off_on_exit).await;
+    info!("goodbye!");
     Ok(())
 }
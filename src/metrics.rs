@@ -0,0 +1,13 @@
+//! What the library records about the volcano and the bluetooth link.
+//! With the `metrics` feature (on by default) it goes into a prometheus
+//! registry, without it every call is a no-op and prometheus isn't pulled in.
+
+#[cfg(feature = "metrics")]
+mod registry;
+#[cfg(not(feature = "metrics"))]
+mod disabled;
+
+#[cfg(feature = "metrics")]
+pub use self::registry::{metrics, Metrics, Timer};
+#[cfg(not(feature = "metrics"))]
+pub use self::disabled::{metrics, Metrics, Timer};
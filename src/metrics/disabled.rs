@@ -0,0 +1,43 @@
+use crate::utils::{HeatingCoolingState, Temperature};
+
+// stands in for the prometheus registry when the metrics feature is off,
+// same calls, nothing recorded
+
+pub struct Timer;
+
+impl Timer {
+
+    pub fn observe_duration(self) {}
+}
+
+pub struct Metrics;
+
+/// Nothing to register without the metrics feature.
+pub fn metrics() -> &'static Metrics {
+    &Metrics
+}
+
+impl Metrics {
+
+    pub fn set_heat_air_state(&self, _state: HeatingCoolingState) {}
+
+    pub fn set_curr_temp(&self, _temp: Temperature) {}
+
+    pub fn set_targ_temp(&self, _temp: Temperature) {}
+
+    pub fn set_connected(&self, _connected: bool) {}
+
+    pub fn inc_reconnects(&self) {}
+
+    pub fn start_ble_read(&self, _characteristic: &str) -> Timer {
+        Timer
+    }
+
+    pub fn start_ble_write(&self, _characteristic: &str) -> Timer {
+        Timer
+    }
+
+    pub fn set_queue_depth(&self, _worker: &str, _depth: usize) {}
+
+    pub fn inc_homekit_writes(&self, _characteristic: &str) {}
+}
@@ -0,0 +1,160 @@
+use std::sync::OnceLock;
+use prometheus::{
+    Encoder,
+    Gauge,
+    HistogramOpts,
+    HistogramTimer,
+    HistogramVec,
+    IntCounter,
+    IntCounterVec,
+    IntGauge,
+    IntGaugeVec,
+    Opts,
+    Registry,
+    TextEncoder,
+};
+
+use crate::utils::{HeatingCoolingState, Temperature};
+
+
+// ble round trips are usually tens of ms, but can take seconds when the link is bad
+const BLE_LATENCY_BUCKETS: &[f64] = &[0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+/// Times a ble round trip until `observe_duration`.
+pub type Timer = HistogramTimer;
+
+/// Everything pele exports on /metrics, shared by the service, workers
+/// and homekit callbacks.
+pub struct Metrics {
+    registry: Registry,
+    curr_temp: Gauge,
+    targ_temp: Gauge,
+    heat_on: IntGauge,
+    air_on: IntGauge,
+    connected: IntGauge,
+    reconnects: IntCounter,
+    ble_read_seconds: HistogramVec,
+    ble_write_seconds: HistogramVec,
+    queue_depth: IntGaugeVec,
+    homekit_writes: IntCounterVec,
+}
+
+/// The process wide metrics, registered on first use.
+pub fn metrics() -> &'static Metrics {
+    static METRICS: OnceLock<Metrics> = OnceLock::new();
+    METRICS.get_or_init(|| Metrics::new().expect("couldn't register metrics"))
+}
+
+impl Metrics {
+
+    fn new() -> prometheus::Result<Metrics> {
+        let registry = Registry::new_custom(Some("pele".into()), None)?;
+
+        let curr_temp = Gauge::new("current_temperature_celsius",
+                                   "Current temperature of the volcano")?;
+        let targ_temp = Gauge::new("target_temperature_celsius",
+                                   "Target temperature of the volcano")?;
+        let heat_on = IntGauge::new("heat_on", "Whether the heater is on")?;
+        let air_on = IntGauge::new("air_on", "Whether the air pump is on")?;
+        let connected = IntGauge::new("connected",
+                                      "Whether the volcano is connected over bluetooth")?;
+        let reconnects = IntCounter::new("ble_reconnects_total",
+                                         "Times the bluetooth link had to be re-established")?;
+        let ble_read_seconds = HistogramVec::new(
+            HistogramOpts::new("ble_read_seconds", "Latency of GATT characteristic reads")
+                          .buckets(BLE_LATENCY_BUCKETS.to_vec()),
+            &["characteristic"])?;
+        let ble_write_seconds = HistogramVec::new(
+            HistogramOpts::new("ble_write_seconds", "Latency of GATT characteristic writes")
+                          .buckets(BLE_LATENCY_BUCKETS.to_vec()),
+            &["characteristic"])?;
+        let queue_depth = IntGaugeVec::new(
+            Opts::new("worker_queue_depth", "Requests waiting in the service channel and the connection queue"),
+            &["worker"])?;
+        let homekit_writes = IntCounterVec::new(
+            Opts::new("homekit_writes_total", "Homekit writes that went out to the volcano, echoes and no-ops left out"),
+            &["characteristic"])?;
+
+        registry.register(Box::new(curr_temp.clone()))?;
+        registry.register(Box::new(targ_temp.clone()))?;
+        registry.register(Box::new(heat_on.clone()))?;
+        registry.register(Box::new(air_on.clone()))?;
+        registry.register(Box::new(connected.clone()))?;
+        registry.register(Box::new(reconnects.clone()))?;
+        registry.register(Box::new(ble_read_seconds.clone()))?;
+        registry.register(Box::new(ble_write_seconds.clone()))?;
+        registry.register(Box::new(queue_depth.clone()))?;
+        registry.register(Box::new(homekit_writes.clone()))?;
+
+        Ok(Metrics {
+            registry,
+            curr_temp,
+            targ_temp,
+            heat_on,
+            air_on,
+            connected,
+            reconnects,
+            ble_read_seconds,
+            ble_write_seconds,
+            queue_depth,
+            homekit_writes,
+        })
+    }
+
+    pub fn set_heat_air_state(&self, state: HeatingCoolingState) {
+        let (heat_on, air_on) = match state {
+            HeatingCoolingState::Off => (0, 0),
+            HeatingCoolingState::Heating => (1, 0),
+            HeatingCoolingState::Cooling => (1, 1),
+        };
+        self.heat_on.set(heat_on);
+        self.air_on.set(air_on);
+    }
+
+    pub fn set_curr_temp(&self, temp: Temperature) {
+        self.curr_temp.set(f64::from(temp.celsius()));
+    }
+
+    pub fn set_targ_temp(&self, temp: Temperature) {
+        self.targ_temp.set(f64::from(temp.celsius()));
+    }
+
+    pub fn set_connected(&self, connected: bool) {
+        self.connected.set(i64::from(connected));
+    }
+
+    pub fn inc_reconnects(&self) {
+        self.reconnects.inc();
+    }
+
+    pub fn start_ble_read(&self, characteristic: &str) -> Timer {
+        self.ble_read_seconds
+            .with_label_values(&[characteristic])
+            .start_timer()
+    }
+
+    pub fn start_ble_write(&self, characteristic: &str) -> Timer {
+        self.ble_write_seconds
+            .with_label_values(&[characteristic])
+            .start_timer()
+    }
+
+    pub fn set_queue_depth(&self, worker: &str, depth: usize) {
+        self.queue_depth
+            .with_label_values(&[worker])
+            .set(depth as i64);
+    }
+
+    pub fn inc_homekit_writes(&self, characteristic: &str) {
+        self.homekit_writes
+            .with_label_values(&[characteristic])
+            .inc();
+    }
+
+    /// Prometheus text exposition format.
+    pub fn render(&self) -> prometheus::Result<String> {
+        let mut buffer = Vec::new();
+        TextEncoder::new().encode(&self.registry.gather(), &mut buffer)?;
+        Ok(String::from_utf8_lossy(&buffer).into_owned())
+    }
+}
@@ -0,0 +1,311 @@
+use std::{sync::Arc, time::Duration};
+use tokio::sync;
+use tracing::{info, warn};
+use hap::MacAddress;
+use rumqttc::{
+    AsyncClient,
+    ClientError,
+    Event,
+    LastWill,
+    MqttOptions,
+    Packet,
+    QoS,
+};
+
+use crate::{
+    bluetooth_service::BluetoothService,
+    cli::MqttArgs,
+    utils::{DeviceState, HeatingCoolingState, Temperature},
+};
+
+mod discovery;
+
+// publishes the volcano's state to an mqtt broker and takes commands back,
+// runs next to the hap server so home assistant folks get a climate + fan entity
+
+const CLIENT_ID_PREFIX: &str = "pele";
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+const AVAILABILITY: &str = "availability";
+const CURR_TEMP: &str = "current_temperature";
+const TARG_TEMP: &str = "target_temperature";
+const MODE: &str = "mode";
+const AIR: &str = "air";
+
+#[derive(Clone)]
+struct Topics {
+    base: String,
+    discovery_prefix: String,
+}
+
+impl Topics {
+
+    fn state(&self, name: &str) -> String {
+        format!("{}/{}", self.base, name)
+    }
+
+    fn command(&self, name: &str) -> String {
+        format!("{}/{}/set", self.base, name)
+    }
+}
+
+// what a command topic asked for, once the payload checks out
+#[derive(Debug, PartialEq)]
+enum Command {
+    TargTemp(Temperature),
+    Heat(bool),
+    Air(bool),
+}
+
+// device_id is homekit's, unique per install, see client_id
+pub async fn run(args: MqttArgs, service: Arc<BluetoothService>, device_id: MacAddress) {
+    let host = match args.mqtt_host {
+        Some(host) => host,
+        None => return,
+    };
+    let client_id = args.mqtt_client_id
+                        .unwrap_or_else(|| client_id(&device_id));
+    let topics = Topics {
+        base: args.mqtt_topic,
+        discovery_prefix: args.mqtt_discovery_prefix,
+    };
+
+    let mut options = MqttOptions::new(client_id, host, args.mqtt_port);
+    options.set_keep_alive(Duration::from_secs(30));
+    options.set_last_will(LastWill::new(topics.state(AVAILABILITY),
+                                        "offline",
+                                        QoS::AtLeastOnce,
+                                        true));
+    if let (Some(user), Some(password)) = (args.mqtt_user, args.mqtt_password) {
+        options.set_credentials(user, password);
+    }
+
+    let (client, mut eventloop) = AsyncClient::new(options, 32);
+
+    let state_client = client.clone();
+    let state_topics = topics.clone();
+    let changes_rx = service.subscribe();
+    tokio::spawn(async move {
+        publish_state_changes(state_client, state_topics, changes_rx).await;
+    });
+
+    // the event loop has to keep getting polled for anything to go out,
+    // so everything that talks to the broker or the volcano gets its own task
+    loop {
+        match eventloop.poll().await {
+            Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                info!("connected to mqtt broker");
+                let client = client.clone();
+                let topics = topics.clone();
+                let state = service.state();
+                tokio::spawn(async move {
+                    if let Err(err) = announce(&client, &topics, &state).await {
+                        warn!(error = %err, "couldn't announce on mqtt");
+                    }
+                });
+            },
+            Ok(Event::Incoming(Packet::Publish(publish))) => {
+                let service = Arc::clone(&service);
+                let topics = topics.clone();
+                let payload = String::from_utf8_lossy(&publish.payload).into_owned();
+                tokio::spawn(async move {
+                    handle_command(&service, &topics, &publish.topic, payload.trim()).await;
+                });
+            },
+            Ok(_) => (),
+            Err(err) => {
+                warn!(error = %err, "mqtt connection error");
+                tokio::time::sleep(RECONNECT_DELAY).await;
+            },
+        }
+    }
+}
+
+// the broker kicks whoever was connected with the same id, so two pele
+// instances on one broker can't share a fixed one
+fn client_id(device_id: &MacAddress) -> String {
+    let hex: String = device_id.as_bytes()
+                               .iter()
+                               .map(|byte| format!("{:02x}", byte))
+                               .collect();
+    format!("{}-{}", CLIENT_ID_PREFIX, hex)
+}
+
+// (re)subscribe and tell home assistant about us, after every connect
+async fn announce(client: &AsyncClient,
+                  topics: &Topics,
+                  state: &DeviceState) -> Result<(), ClientError> {
+    for name in [TARG_TEMP, MODE, AIR] {
+        client.subscribe(topics.command(name), QoS::AtLeastOnce).await?;
+    }
+    for (topic, config) in discovery::configs(topics) {
+        client.publish(topic, QoS::AtLeastOnce, true, config.to_string()).await?;
+    }
+    publish_state(client, topics, state).await
+}
+
+async fn publish_state_changes(client: AsyncClient,
+                               topics: Topics,
+                               mut state_rx: sync::watch::Receiver<DeviceState>) {
+    while state_rx.changed().await.is_ok() {
+        let state = *state_rx.borrow();
+        if let Err(err) = publish_state(&client, &topics, &state).await {
+            warn!(error = %err, "couldn't publish state on mqtt");
+        }
+    }
+}
+
+async fn publish_state(client: &AsyncClient,
+                       topics: &Topics,
+                       state: &DeviceState) -> Result<(), ClientError> {
+    let availability = if state.connected { "online" } else { "offline" };
+    client.publish(topics.state(AVAILABILITY), QoS::AtLeastOnce, true, availability).await?;
+
+    if let Some(curr_temp) = state.curr_temp {
+        let payload = format!("{:.1}", curr_temp.celsius());
+        client.publish(topics.state(CURR_TEMP), QoS::AtLeastOnce, true, payload).await?;
+    }
+    if let Some(targ_temp) = state.targ_temp {
+        let payload = format!("{:.1}", targ_temp.celsius());
+        client.publish(topics.state(TARG_TEMP), QoS::AtLeastOnce, true, payload).await?;
+    }
+    if let Some(heat_air_state) = state.heat_air_state {
+        let mode = if heat_air_state.is_heat_on() { "heat" } else { "off" };
+        let air = if heat_air_state.is_air_on() { "ON" } else { "OFF" };
+        client.publish(topics.state(MODE), QoS::AtLeastOnce, true, mode).await?;
+        client.publish(topics.state(AIR), QoS::AtLeastOnce, true, air).await?;
+    }
+    Ok(())
+}
+
+async fn handle_command(service: &BluetoothService,
+                        topics: &Topics,
+                        topic: &str,
+                        payload: &str) {
+    match parse_command(topics, topic, payload) {
+        Some(Command::TargTemp(temp)) => match service.write_temp(temp).await {
+            Some(Ok(())) => (),
+            Some(Err(err)) => warn!(error = %err, payload,
+                                    "volcano didn't take the mqtt target temperature"),
+            None => warn!(payload, "couldn't reach the volcano for the mqtt target temperature"),
+        },
+        Some(Command::Heat(is_on)) => {
+            let curr_state = service.get_curr_heat_air_state().await;
+            let state = HeatingCoolingState::with_heat(is_on, curr_state);
+            set_heat_air_state(service, state).await;
+        },
+        Some(Command::Air(is_on)) => {
+            let curr_state = service.get_curr_heat_air_state().await;
+            let state = HeatingCoolingState::with_air(is_on, curr_state);
+            set_heat_air_state(service, state).await;
+        },
+        None => (),
+    }
+}
+
+// None for topics that aren't ours and payloads we can't use, the
+// latter get logged
+fn parse_command(topics: &Topics, topic: &str, payload: &str) -> Option<Command> {
+    if topic == topics.command(TARG_TEMP) {
+        let temp = match payload.parse::<f32>() {
+            Ok(cel_val) => Temperature::from_celsius(cel_val),
+            Err(_) => {
+                warn!(payload, "ignoring bad target temperature over mqtt");
+                return None;
+            },
+        };
+        if !temp.is_settable() {
+            warn!(payload, "ignoring out of range target temperature over mqtt");
+            return None;
+        }
+        Some(Command::TargTemp(temp))
+    } else if topic == topics.command(MODE) {
+        match payload {
+            "heat" => Some(Command::Heat(true)),
+            "off" => Some(Command::Heat(false)),
+            _ => {
+                warn!(payload, "ignoring unknown mode over mqtt");
+                None
+            },
+        }
+    } else if topic == topics.command(AIR) {
+        match payload {
+            "ON" => Some(Command::Air(true)),
+            "OFF" => Some(Command::Air(false)),
+            _ => {
+                warn!(payload, "ignoring unknown air state over mqtt");
+                None
+            },
+        }
+    } else {
+        None
+    }
+}
+
+async fn set_heat_air_state(service: &BluetoothService, state: HeatingCoolingState) {
+    match service.set_curr_heat_air_state(state).await {
+        Some(Ok(())) => (),
+        Some(Err(err)) => warn!(error = %err, ?state, "volcano didn't take the mqtt command"),
+        None => warn!(?state, "couldn't reach the volcano for the mqtt command"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn topics() -> Topics {
+        Topics {
+            base: "pele".into(),
+            discovery_prefix: "homeassistant".into(),
+        }
+    }
+
+    #[test]
+    fn client_id_comes_from_the_device_id() {
+        let device_id = MacAddress::new([0x02, 0x1a, 0x2b, 0x3c, 0x4d, 0x5e]);
+        assert_eq!(client_id(&device_id), "pele-021a2b3c4d5e");
+    }
+
+    #[test]
+    fn parses_target_temperature() {
+        let command = parse_command(&topics(), "pele/target_temperature/set", "185.5");
+        assert_eq!(command, Some(Command::TargTemp(Temperature::from_celsius(185.5))));
+    }
+
+    #[test]
+    fn rejects_bad_target_temperatures() {
+        let topics = topics();
+        assert_eq!(parse_command(&topics, "pele/target_temperature/set", "hot"), None);
+        assert_eq!(parse_command(&topics, "pele/target_temperature/set", ""), None);
+        // outside of what the volcano can do
+        assert_eq!(parse_command(&topics, "pele/target_temperature/set", "39"), None);
+        assert_eq!(parse_command(&topics, "pele/target_temperature/set", "231"), None);
+    }
+
+    #[test]
+    fn parses_mode() {
+        let topics = topics();
+        assert_eq!(parse_command(&topics, "pele/mode/set", "heat"), Some(Command::Heat(true)));
+        assert_eq!(parse_command(&topics, "pele/mode/set", "off"), Some(Command::Heat(false)));
+        // home assistant only ever sends the modes we announced
+        assert_eq!(parse_command(&topics, "pele/mode/set", "cool"), None);
+        assert_eq!(parse_command(&topics, "pele/mode/set", "HEAT"), None);
+    }
+
+    #[test]
+    fn parses_air() {
+        let topics = topics();
+        assert_eq!(parse_command(&topics, "pele/air/set", "ON"), Some(Command::Air(true)));
+        assert_eq!(parse_command(&topics, "pele/air/set", "OFF"), Some(Command::Air(false)));
+        assert_eq!(parse_command(&topics, "pele/air/set", "on"), None);
+    }
+
+    #[test]
+    fn ignores_other_topics() {
+        let topics = topics();
+        // our own state topics come back if anything subscribes to them
+        assert_eq!(parse_command(&topics, "pele/mode", "heat"), None);
+        assert_eq!(parse_command(&topics, "other/mode/set", "heat"), None);
+    }
+}
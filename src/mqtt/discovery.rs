@@ -0,0 +1,107 @@
+use hap::serde_json::{json, Value};
+
+use crate::{
+    mqtt::{Topics, AIR, AVAILABILITY, CURR_TEMP, MODE, TARG_TEMP},
+    utils::{DEVICE_MAX_TEMP_C, DEVICE_MIN_TEMP_C},
+};
+
+// home assistant mqtt discovery payloads, see
+// https://www.home-assistant.io/integrations/mqtt/#mqtt-discovery
+
+// (config topic, payload) for every entity we expose
+pub fn configs(topics: &Topics) -> Vec<(String, Value)> {
+    let object_id = topics.base.replace('/', "_");
+    let device = json!({
+        "identifiers": [object_id],
+        "name": "Volcano",
+        "manufacturer": "Storz & Bickel",
+        "model": "Volcano Hybrid",
+    });
+
+    let climate = json!({
+        "name": "Volcano",
+        "unique_id": format!("{}_climate", object_id),
+        "device": device,
+        "availability_topic": topics.state(AVAILABILITY),
+        "modes": ["off", "heat"],
+        "mode_state_topic": topics.state(MODE),
+        "mode_command_topic": topics.command(MODE),
+        "current_temperature_topic": topics.state(CURR_TEMP),
+        "temperature_state_topic": topics.state(TARG_TEMP),
+        "temperature_command_topic": topics.command(TARG_TEMP),
+        "temperature_unit": "C",
+        "min_temp": DEVICE_MIN_TEMP_C,
+        "max_temp": DEVICE_MAX_TEMP_C,
+        "temp_step": 1,
+        "precision": 0.1,
+    });
+
+    let fan = json!({
+        "name": "Volcano air pump",
+        "unique_id": format!("{}_air", object_id),
+        "device": device,
+        "availability_topic": topics.state(AVAILABILITY),
+        "state_topic": topics.state(AIR),
+        "command_topic": topics.command(AIR),
+        "payload_on": "ON",
+        "payload_off": "OFF",
+    });
+
+    vec![
+        (format!("{}/climate/{}/config", topics.discovery_prefix, object_id), climate),
+        (format!("{}/fan/{}_air/config", topics.discovery_prefix, object_id), fan),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn configs_for(base: &str) -> Vec<(String, Value)> {
+        configs(&Topics {
+            base: base.into(),
+            discovery_prefix: "homeassistant".into(),
+        })
+    }
+
+    #[test]
+    fn climate_config() {
+        let configs = configs_for("pele");
+        let (topic, climate) = &configs[0];
+        assert_eq!(topic, "homeassistant/climate/pele/config");
+        assert_eq!(climate["unique_id"], "pele_climate");
+        assert_eq!(climate["availability_topic"], "pele/availability");
+        assert_eq!(climate["modes"], json!(["off", "heat"]));
+        assert_eq!(climate["mode_state_topic"], "pele/mode");
+        assert_eq!(climate["mode_command_topic"], "pele/mode/set");
+        assert_eq!(climate["current_temperature_topic"], "pele/current_temperature");
+        assert_eq!(climate["temperature_state_topic"], "pele/target_temperature");
+        assert_eq!(climate["temperature_command_topic"], "pele/target_temperature/set");
+        assert_eq!(climate["min_temp"], json!(DEVICE_MIN_TEMP_C));
+        assert_eq!(climate["max_temp"], json!(DEVICE_MAX_TEMP_C));
+        assert_eq!(climate["device"]["identifiers"], json!(["pele"]));
+    }
+
+    #[test]
+    fn fan_config() {
+        let configs = configs_for("pele");
+        let (topic, fan) = &configs[1];
+        assert_eq!(topic, "homeassistant/fan/pele_air/config");
+        assert_eq!(fan["unique_id"], "pele_air");
+        assert_eq!(fan["state_topic"], "pele/air");
+        assert_eq!(fan["command_topic"], "pele/air/set");
+        assert_eq!(fan["payload_on"], "ON");
+        assert_eq!(fan["payload_off"], "OFF");
+        // both entities show up under the same device
+        assert_eq!(fan["device"], configs[0].1["device"]);
+    }
+
+    #[test]
+    fn nested_base_topic_makes_flat_ids() {
+        let configs = configs_for("home/volcano");
+        assert_eq!(configs[0].0, "homeassistant/climate/home_volcano/config");
+        assert_eq!(configs[0].1["unique_id"], "home_volcano_climate");
+        assert_eq!(configs[0].1["mode_command_topic"], "home/volcano/mode/set");
+        assert_eq!(configs[1].0, "homeassistant/fan/home_volcano_air/config");
+    }
+}
@@ -0,0 +1,81 @@
+use hap::{
+    storage::{FileStorage, Storage},
+    BonjourStatusFlag,
+};
+
+use crate::{
+    cli::{PairingsArgs, PairingsCommand},
+    Result,
+};
+
+// backs `pele pairings`, straight on hap's stored pairing records. a
+// running pele keeps advertising what it started with, so it has to be
+// restarted for controllers to see the change
+
+pub async fn manage(args: PairingsArgs) -> Result<()> {
+    let mut storage = FileStorage::current_dir().await?;
+    match args.command {
+        PairingsCommand::List => list(&storage).await,
+        PairingsCommand::Remove { id } => remove(&mut storage, &id).await,
+        PairingsCommand::Reset => reset(&mut storage).await,
+    }
+}
+
+async fn list(storage: &FileStorage) -> Result<()> {
+    let pairings = storage.list_pairings().await?;
+    if pairings.is_empty() {
+        println!("not paired with anything");
+        return Ok(());
+    }
+
+    println!("{:<36} {:<11}", "id", "permissions");
+    for pairing in pairings {
+        println!("{:<36} {:<11}",
+                 pairing.id.to_string(),
+                 format!("{:?}", pairing.permissions).to_lowercase());
+    }
+    Ok(())
+}
+
+async fn remove(storage: &mut FileStorage, id: &str) -> Result<()> {
+    let pairings = storage.list_pairings().await?;
+    let pairing = pairings.iter()
+                          .find(|pairing| pairing.id.to_string().eq_ignore_ascii_case(id))
+                          .ok_or("no pairing with that id, see `pele pairings list`")?;
+    storage.delete_pairing(&pairing.id).await?;
+    println!("removed {}", pairing.id);
+
+    if pairings.len() == 1 {
+        mark_unpaired(storage, false).await?;
+    }
+    println!("restart pele for this to take effect");
+    Ok(())
+}
+
+async fn reset(storage: &mut FileStorage) -> Result<()> {
+    let pairings = storage.list_pairings().await?;
+    for pairing in &pairings {
+        storage.delete_pairing(&pairing.id).await?;
+    }
+    println!("removed {} pairing(s)", pairings.len());
+
+    mark_unpaired(storage, true).await?;
+    println!("restart pele, then add it in the home app again");
+    Ok(())
+}
+
+// advertise as pairable again, bumping the config number tells
+// controllers that still have us cached that something changed
+async fn mark_unpaired(storage: &mut FileStorage, should_bump: bool) -> Result<()> {
+    let mut config = match storage.load_config().await {
+        Ok(config) => config,
+        // never ran, nothing to update
+        Err(_) => return Ok(()),
+    };
+    config.status_flag = BonjourStatusFlag::NotPaired;
+    if should_bump {
+        config.configuration_number += 1;
+    }
+    storage.save_config(&config).await?;
+    Ok(())
+}
@@ -0,0 +1,85 @@
+use std::sync::Arc;
+use tokio::{sync, time::{Duration, Instant}};
+use tracing::debug;
+
+use crate::{
+    bluetooth_service::BluetoothService,
+    cli::PollArgs,
+    utils::DeviceState,
+};
+
+// how long the update loop waits before polling the volcano again. heat-up
+// wants a smooth curve, a volcano that's off for hours doesn't need
+// watching, and one that's gone shouldn't get hammered with reconnects
+
+pub struct PollRate {
+    active: Duration,
+    idle: Duration,
+    disconnected: Duration,
+    disconnected_max: Duration,
+    disconnected_polls: u32,
+}
+
+impl PollRate {
+
+    pub fn new(args: &PollArgs) -> PollRate {
+        PollRate {
+            active: Duration::from_millis(args.poll_active_ms),
+            idle: Duration::from_millis(args.poll_idle_ms),
+            disconnected: Duration::from_millis(args.poll_disconnected_ms),
+            disconnected_max: Duration::from_millis(args.poll_disconnected_max_ms),
+            disconnected_polls: 0,
+        }
+    }
+
+    // the first poll goes out right after startup at the active rate
+    pub fn initial(&self) -> Duration {
+        self.active
+    }
+
+    pub fn next(&mut self, state: &DeviceState) -> Duration {
+        if !state.connected {
+            // doubles with every poll that still finds it gone
+            let backoff = self.disconnected
+                              .saturating_mul(1 << self.disconnected_polls.min(16))
+                              .min(self.disconnected_max);
+            self.disconnected_polls += 1;
+            debug!(?backoff, "volcano disconnected, backing off");
+            return backoff;
+        }
+        self.disconnected_polls = 0;
+
+        let is_active = state.heat_air_state
+                             .map_or(false, |state| state.is_heat_on() || state.is_air_on());
+        if is_active { self.active } else { self.idle }
+    }
+}
+
+// fills the gaps between the reads homekit and the api make on their own
+// (on_read goes through the same cached reads), so the state stays fresh
+// for the subscribers when nobody is looking. anything read in the
+// meantime pushes the next poll back, ble stays idle while it's in use
+pub async fn poll_loop(bluetooth_service: Arc<BluetoothService>,
+                       mut poll_rate: PollRate,
+                       mut shutdown_rx: sync::watch::Receiver<bool>) {
+    let mut poll_interval = poll_rate.initial();
+    let mut polled_at = Instant::now();
+    loop {
+        let read_at = bluetooth_service.read_at()
+                                       .map_or(polled_at, |read_at| read_at.max(polled_at));
+        let due_at = read_at + poll_interval;
+        if due_at > Instant::now() {
+            // wait until the next poll is due, unless we're shutting down
+            tokio::select! {
+                _ = tokio::time::sleep_until(due_at) => (),
+                _ = shutdown_rx.changed() => break,
+            }
+            // someone may have read it meanwhile
+            continue;
+        }
+
+        let state = bluetooth_service.poll().await;
+        polled_at = Instant::now();
+        poll_interval = poll_rate.next(&state);
+    }
+}
@@ -0,0 +1,71 @@
+use std::time::Duration;
+use bluer::Device;
+
+use crate::{
+    bluetooth_service::discovery::{self, DeviceInfo},
+    cli::ScanArgs,
+    Result,
+};
+
+// backs `pele scan`, for finding the volcano's address on a fresh setup
+
+pub async fn print_scan(args: ScanArgs) -> Result<()> {
+    eprintln!("scanning for {}s...", args.seconds);
+    let volcanos = discovery::scan_volcanos(args.adapter.as_deref(),
+                                            Duration::from_secs(args.seconds)).await?;
+    if volcanos.is_empty() {
+        println!("no volcanos found");
+        return Ok(());
+    }
+
+    println!("{:<17} {:<16} {:>5} {:<6} {:<9} {:<16} {:<12} {:<10}",
+             "address", "name", "rssi", "paired", "connected", "model", "serial", "firmware");
+    for device in volcanos {
+        // read these before the quick connect changes them
+        let name = device.name().await?.unwrap_or_default();
+        let rssi = device.rssi().await?;
+        let is_paired = device.is_paired().await?;
+        let is_connected = device.is_connected().await?;
+
+        let info = if args.no_connect {
+            DeviceInfo::default()
+        } else {
+            quick_info(&device, is_connected).await
+        };
+
+        println!("{:<17} {:<16} {:>5} {:<6} {:<9} {:<16} {:<12} {:<10}",
+                 device.address(),
+                 name,
+                 rssi.map_or("-".into(), |rssi| rssi.to_string()),
+                 yes_no(is_paired),
+                 yes_no(is_connected),
+                 info.model.as_deref().unwrap_or("-"),
+                 info.serial.as_deref().unwrap_or("-"),
+                 info.firmware.as_deref().unwrap_or("-"));
+    }
+    Ok(())
+}
+
+// leaves the connection the way it found it
+async fn quick_info(device: &Device, was_connected: bool) -> DeviceInfo {
+    let info = match tokio::time::timeout(discovery::INFO_TIMEOUT,
+                                          discovery::read_device_info(device)).await {
+        Ok(Ok(info)) => info,
+        Ok(Err(err)) => {
+            eprintln!("couldn't read {}: {}", device.address(), err);
+            DeviceInfo::default()
+        },
+        Err(_) => {
+            eprintln!("timed out reading {}", device.address());
+            DeviceInfo::default()
+        },
+    };
+    if !was_connected {
+        let _ = device.disconnect().await;
+    }
+    info
+}
+
+fn yes_no(val: bool) -> &'static str {
+    if val { "yes" } else { "no" }
+}
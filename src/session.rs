@@ -0,0 +1,373 @@
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+use crate::{
+    utils::{ApproxEq, DeviceState, HeatingCoolingState, Temperature},
+    Result,
+};
+
+pub mod store;
+
+use crate::session::store::SessionStore;
+
+
+// how close the current temp has to get to the target
+// before we count it as "up to temp"
+const AT_TEMP_TOLERANCE_C: f32 = 1.0;
+
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SessionEnd {
+    // heat went off, either from a controller, the panel or auto-shutoff
+    HeatOff,
+    // pele itself was stopped mid session
+    Shutdown,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SessionRecord {
+    pub start: DateTime<Local>,
+    pub end: DateTime<Local>,
+    pub end_reason: SessionEnd,
+    // every target temp used, in the order they were set
+    pub targ_temps_c: Vec<f32>,
+    pub max_temp_c: Option<f32>,
+    pub time_to_temp_secs: Option<i64>,
+    pub air_secs: i64,
+}
+
+impl SessionRecord {
+
+    pub fn duration_secs(&self) -> i64 {
+        (self.end - self.start).num_seconds()
+    }
+}
+
+struct ActiveSession {
+    start: DateTime<Local>,
+    targ_temps_c: Vec<f32>,
+    max_temp_c: Option<f32>,
+    time_to_temp_secs: Option<i64>,
+    air_secs: i64,
+    air_since: Option<DateTime<Local>>,
+}
+
+impl ActiveSession {
+
+    fn new(start: DateTime<Local>) -> ActiveSession {
+        ActiveSession {
+            start,
+            targ_temps_c: Vec::new(),
+            max_temp_c: None,
+            time_to_temp_secs: None,
+            air_secs: 0,
+            air_since: None,
+        }
+    }
+
+    fn update(&mut self, state: &DeviceState, heat_air_state: HeatingCoolingState,
+              now: DateTime<Local>) {
+        // homekit's values come back through the offset, 185.0 from the
+        // device and 185.0222 from homekit are the same target
+        if let Some(targ_temp) = state.targ_temp {
+            let is_same = self.targ_temps_c
+                              .last()
+                              .is_some_and(|last_c| Temperature::from_celsius(*last_c)
+                                                        .approx_eq(&targ_temp));
+            if !is_same {
+                // the volcano works in tenths
+                self.targ_temps_c.push((targ_temp.celsius() * 10.0).round() / 10.0);
+            }
+        }
+
+        if let Some(curr_temp) = state.curr_temp {
+            let curr_c = curr_temp.celsius();
+            self.max_temp_c = Some(self.max_temp_c.map_or(curr_c, |max| max.max(curr_c)));
+
+            if let (None, Some(targ_temp)) = (self.time_to_temp_secs, state.targ_temp) {
+                if curr_c >= targ_temp.celsius() - AT_TEMP_TOLERANCE_C {
+                    self.time_to_temp_secs = Some((now - self.start).num_seconds());
+                }
+            }
+        }
+
+        // the "cooling" state is heat with the air pump running
+        match (heat_air_state, self.air_since) {
+            (HeatingCoolingState::Cooling, None) => self.air_since = Some(now),
+            (HeatingCoolingState::Cooling, Some(_)) => (),
+            (_, Some(_)) => self.stop_air(now),
+            (_, None) => (),
+        }
+    }
+
+    fn stop_air(&mut self, now: DateTime<Local>) {
+        if let Some(air_since) = self.air_since.take() {
+            self.air_secs += (now - air_since).num_seconds();
+        }
+    }
+
+    fn finish(mut self, now: DateTime<Local>, end_reason: SessionEnd) -> SessionRecord {
+        self.stop_air(now);
+        SessionRecord {
+            start: self.start,
+            end: now,
+            end_reason,
+            targ_temps_c: self.targ_temps_c,
+            max_temp_c: self.max_temp_c,
+            time_to_temp_secs: self.time_to_temp_secs,
+            air_secs: self.air_secs,
+        }
+    }
+}
+
+// turns the stream of polled device states into sessions,
+// a session runs from heat-on until the heat goes off again
+#[derive(Default)]
+pub struct SessionTracker {
+    active: Option<ActiveSession>,
+}
+
+impl SessionTracker {
+
+    pub fn observe(&mut self, state: &DeviceState,
+                   now: DateTime<Local>) -> Option<SessionRecord> {
+        // values left over from the last run didn't happen now
+        if state.stale_since.is_some() {
+            return None;
+        }
+        let heat_air_state = state.heat_air_state?;
+        if heat_air_state.is_heat_on() {
+            self.active
+                .get_or_insert_with(|| ActiveSession::new(now))
+                .update(state, heat_air_state, now);
+            return None;
+        }
+
+        self.finish(now, SessionEnd::HeatOff)
+    }
+
+    pub fn finish(&mut self, now: DateTime<Local>,
+                  end_reason: SessionEnd) -> Option<SessionRecord> {
+        self.active
+            .take()
+            .map(|session| session.finish(now, end_reason))
+    }
+}
+
+// glues the tracker to the on-disk store
+pub struct SessionRecorder {
+    tracker: SessionTracker,
+    store: SessionStore,
+}
+
+impl SessionRecorder {
+
+    pub fn new(store: SessionStore) -> SessionRecorder {
+        SessionRecorder {
+            tracker: SessionTracker::default(),
+            store,
+        }
+    }
+
+    pub async fn observe(&mut self, state: &DeviceState) -> Result<()> {
+        match self.tracker.observe(state, Local::now()) {
+            Some(record) => self.save(record).await,
+            None => Ok(()),
+        }
+    }
+
+    pub async fn finish(&mut self) -> Result<()> {
+        match self.tracker.finish(Local::now(), SessionEnd::Shutdown) {
+            Some(record) => self.save(record).await,
+            None => Ok(()),
+        }
+    }
+
+    async fn save(&self, record: SessionRecord) -> Result<()> {
+        info!(duration_secs = record.duration_secs(),
+              end_reason = ?record.end_reason,
+              "session finished");
+        self.store.append(&record).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Duration;
+    use super::*;
+
+    fn state(heat_air_state: HeatingCoolingState, curr_c: f32, targ_c: f32) -> DeviceState {
+        DeviceState {
+            connected: true,
+            curr_temp: Some(Temperature::from_celsius(curr_c)),
+            targ_temp: Some(Temperature::from_celsius(targ_c)),
+            heat_air_state: Some(heat_air_state),
+            ..Default::default()
+        }
+    }
+
+    fn secs(secs: i64) -> Duration {
+        Duration::seconds(secs)
+    }
+
+    #[test]
+    fn nothing_while_the_heat_is_off() {
+        let now = Local::now();
+        let mut tracker = SessionTracker::default();
+        assert!(tracker.observe(&state(HeatingCoolingState::Off, 25.0, 185.0), now).is_none());
+        assert!(tracker.observe(&DeviceState::default(), now + secs(1)).is_none());
+        assert!(tracker.finish(now + secs(2), SessionEnd::Shutdown).is_none());
+    }
+
+    #[test]
+    fn heat_on_to_heat_off_is_one_session() {
+        let start = Local::now();
+        let mut tracker = SessionTracker::default();
+        assert!(tracker.observe(&state(HeatingCoolingState::Heating, 25.0, 185.0), start).is_none());
+        assert!(tracker.observe(&state(HeatingCoolingState::Heating, 120.0, 185.0),
+                                start + secs(60)).is_none());
+
+        let record = tracker.observe(&state(HeatingCoolingState::Off, 180.0, 185.0),
+                                     start + secs(300))
+                            .unwrap();
+        assert_eq!(record.start, start);
+        assert_eq!(record.duration_secs(), 300);
+        assert_eq!(record.end_reason, SessionEnd::HeatOff);
+
+        // and it's over, the next off doesn't end it again
+        assert!(tracker.observe(&state(HeatingCoolingState::Off, 170.0, 185.0),
+                                start + secs(310)).is_none());
+    }
+
+    #[test]
+    fn missing_heat_state_doesnt_end_the_session() {
+        let start = Local::now();
+        let mut tracker = SessionTracker::default();
+        tracker.observe(&state(HeatingCoolingState::Heating, 25.0, 185.0), start);
+        assert!(tracker.observe(&DeviceState::default(), start + secs(10)).is_none());
+
+        let record = tracker.observe(&state(HeatingCoolingState::Off, 25.0, 185.0),
+                                     start + secs(20))
+                            .unwrap();
+        assert_eq!(record.duration_secs(), 20);
+    }
+
+    #[test]
+    fn peak_temp_and_time_to_temp() {
+        let start = Local::now();
+        let mut tracker = SessionTracker::default();
+        tracker.observe(&state(HeatingCoolingState::Heating, 25.0, 185.0), start);
+        tracker.observe(&state(HeatingCoolingState::Heating, 150.0, 185.0), start + secs(120));
+        // within the tolerance counts as there
+        tracker.observe(&state(HeatingCoolingState::Heating, 184.5, 185.0), start + secs(200));
+        tracker.observe(&state(HeatingCoolingState::Heating, 186.0, 185.0), start + secs(260));
+        tracker.observe(&state(HeatingCoolingState::Heating, 185.0, 185.0), start + secs(320));
+
+        let record = tracker.observe(&state(HeatingCoolingState::Off, 184.0, 185.0),
+                                     start + secs(400))
+                            .unwrap();
+        assert_eq!(record.max_temp_c, Some(186.0));
+        assert_eq!(record.time_to_temp_secs, Some(200));
+    }
+
+    #[test]
+    fn never_reaching_temp() {
+        let start = Local::now();
+        let mut tracker = SessionTracker::default();
+        tracker.observe(&state(HeatingCoolingState::Heating, 25.0, 185.0), start);
+        tracker.observe(&state(HeatingCoolingState::Heating, 90.0, 185.0), start + secs(60));
+
+        let record = tracker.observe(&state(HeatingCoolingState::Off, 90.0, 185.0),
+                                     start + secs(90))
+                            .unwrap();
+        assert_eq!(record.max_temp_c, Some(90.0));
+        assert_eq!(record.time_to_temp_secs, None);
+    }
+
+    #[test]
+    fn target_temps_in_order() {
+        let start = Local::now();
+        let mut tracker = SessionTracker::default();
+        tracker.observe(&state(HeatingCoolingState::Heating, 25.0, 180.0), start);
+        tracker.observe(&state(HeatingCoolingState::Heating, 60.0, 180.0), start + secs(10));
+        tracker.observe(&state(HeatingCoolingState::Heating, 90.0, 190.0), start + secs(20));
+        tracker.observe(&state(HeatingCoolingState::Heating, 120.0, 180.0), start + secs(30));
+
+        let record = tracker.observe(&state(HeatingCoolingState::Off, 120.0, 180.0),
+                                     start + secs(40))
+                            .unwrap();
+        assert_eq!(record.targ_temps_c, vec![180.0, 190.0, 180.0]);
+    }
+
+    #[test]
+    fn homekit_and_device_target_temps_are_the_same_temp() {
+        let start = Local::now();
+        let mut tracker = SessionTracker::default();
+        let mut device_state = state(HeatingCoolingState::Heating, 25.0, 180.0);
+        tracker.observe(&device_state, start);
+
+        // homekit set 185, its 12.8 comes back as 185.0222
+        device_state.targ_temp = Some(Temperature::from_homekit_val(12.8, true));
+        tracker.observe(&device_state, start + secs(5));
+        // and the volcano reads back what it took
+        device_state.targ_temp = Some(Temperature::from_celsius(185.0));
+        device_state.curr_temp = Some(Temperature::from_celsius(60.0));
+        tracker.observe(&device_state, start + secs(10));
+
+        device_state.heat_air_state = Some(HeatingCoolingState::Off);
+        let record = tracker.observe(&device_state, start + secs(20)).unwrap();
+        assert_eq!(record.targ_temps_c, vec![180.0, 185.0]);
+    }
+
+    #[test]
+    fn air_time_adds_up() {
+        let start = Local::now();
+        let mut tracker = SessionTracker::default();
+        tracker.observe(&state(HeatingCoolingState::Heating, 185.0, 185.0), start);
+        tracker.observe(&state(HeatingCoolingState::Cooling, 185.0, 185.0), start + secs(10));
+        tracker.observe(&state(HeatingCoolingState::Heating, 185.0, 185.0), start + secs(40));
+        tracker.observe(&state(HeatingCoolingState::Cooling, 185.0, 185.0), start + secs(100));
+
+        // heat off with the air still running ends both
+        let record = tracker.observe(&state(HeatingCoolingState::Off, 185.0, 185.0),
+                                     start + secs(115))
+                            .unwrap();
+        assert_eq!(record.air_secs, 45);
+    }
+
+    #[test]
+    fn stale_heat_on_doesnt_start_a_session() {
+        let start = Local::now();
+        let mut tracker = SessionTracker::default();
+        // saved with the heat on, then pele restarted
+        let mut device_state = DeviceState {
+            stale_since: Some(std::time::SystemTime::now()),
+            ..state(HeatingCoolingState::Heating, 185.0, 185.0)
+        };
+        assert!(tracker.observe(&device_state, start).is_none());
+
+        // the temp comes back first, the old heat state mustn't pass for fresh
+        device_state.apply_read(|state| state.curr_temp = Some(Temperature::from_celsius(60.0)));
+        assert_eq!(device_state.heat_air_state, None);
+        assert!(tracker.observe(&device_state, start + secs(1)).is_none());
+
+        // the heat was off all along
+        device_state.apply_read(|state| state.heat_air_state = Some(HeatingCoolingState::Off));
+        assert!(tracker.observe(&device_state, start + secs(2)).is_none());
+        assert!(tracker.finish(start + secs(3), SessionEnd::Shutdown).is_none());
+    }
+
+    #[test]
+    fn shutdown_mid_session() {
+        let start = Local::now();
+        let mut tracker = SessionTracker::default();
+        tracker.observe(&state(HeatingCoolingState::Cooling, 185.0, 185.0), start);
+
+        let record = tracker.finish(start + secs(30), SessionEnd::Shutdown).unwrap();
+        assert_eq!(record.end_reason, SessionEnd::Shutdown);
+        assert_eq!(record.duration_secs(), 30);
+        assert_eq!(record.air_secs, 30);
+        assert!(tracker.finish(start + secs(40), SessionEnd::Shutdown).is_none());
+    }
+}
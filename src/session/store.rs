@@ -0,0 +1,74 @@
+use std::path::PathBuf;
+use tokio::{
+    fs::{self, OpenOptions},
+    io::AsyncWriteExt,
+};
+use hap::serde_json;
+use tracing::warn;
+
+use crate::{
+    session::SessionRecord,
+    Result,
+};
+
+const SESSIONS_FILE_NAME: &str = "sessions.jsonl";
+
+// append-only jsonl file, one finished session per line
+
+pub struct SessionStore {
+    path: PathBuf,
+}
+
+impl SessionStore {
+
+    pub fn new(path: PathBuf) -> SessionStore {
+        SessionStore { path }
+    }
+
+    pub fn current_dir() -> Result<SessionStore> {
+        let path = data_dir()?.join(SESSIONS_FILE_NAME);
+        Ok(SessionStore::new(path))
+    }
+
+    pub async fn append(&self, record: &SessionRecord) -> Result<()> {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+        let mut line = serde_json::to_vec(record)?;
+        line.push(b'\n');
+
+        let mut file = OpenOptions::new().create(true)
+                                         .append(true)
+                                         .open(&self.path)
+                                         .await?;
+        file.write_all(&line).await?;
+        file.flush().await?;
+        Ok(())
+    }
+
+    pub async fn load_all(&self) -> Result<Vec<SessionRecord>> {
+        let contents = match fs::read_to_string(&self.path).await {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(err) => return Err(Box::new(err)),
+        };
+
+        let mut records = Vec::new();
+        for (line_num, line) in contents.lines().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            // a torn last line from a crash shouldn't hide the rest of the history
+            match serde_json::from_str(line) {
+                Ok(record) => records.push(record),
+                Err(err) => warn!(line = line_num + 1, error = %err, "skipping bad session"),
+            }
+        }
+        Ok(records)
+    }
+}
+
+// same place hap's FileStorage::current_dir() keeps its stuff
+fn data_dir() -> std::io::Result<PathBuf> {
+    Ok(std::env::current_dir()?.join("data"))
+}
@@ -0,0 +1,179 @@
+use hap::{
+    storage::{FileStorage, Storage},
+    Config,
+    MacAddress,
+    Pin,
+};
+use qrcode::{render::unicode, QrCode};
+use rand::{distributions::Uniform, Rng};
+use tracing::warn;
+
+use crate::Result;
+
+// everything that identifies this install to homekit: a random pin and
+// device id for the config (generated once, hap keeps them with the rest
+// of it), and the setup id + X-HM:// uri behind the setup qr code
+
+const SETUP_ID_KEY: &str = "setup_id";
+const SETUP_ID_CHARS: &[u8] = b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZ";
+
+// what every install used before they were random, see warn_if_shared
+const SHARED_DEVICE_ID: [u8; 6] = [20, 20, 30, 40, 50, 60];
+
+// setup payload flags, we're only reachable over ip
+const SETUP_FLAG_IP: u64 = 2;
+
+pub fn new_pin() -> Result<Pin> {
+    let digits = Uniform::new_inclusive(0, 9);
+    let mut rng = rand::thread_rng();
+    loop {
+        let mut pin = [0u8; 8];
+        pin.iter_mut()
+           .for_each(|digit| *digit = rng.sample(digits));
+        if !is_trivial(&pin) {
+            return Ok(Pin::new(pin)?);
+        }
+    }
+}
+
+// the codes the hap spec won't let a controller pair with
+fn is_trivial(pin: &[u8; 8]) -> bool {
+    pin.iter().all(|digit| *digit == pin[0])
+        || *pin == [1, 2, 3, 4, 5, 6, 7, 8]
+        || *pin == [8, 7, 6, 5, 4, 3, 2, 1]
+}
+
+pub fn new_device_id() -> MacAddress {
+    let mut device_id: [u8; 6] = rand::random();
+    // locally administered unicast, so it can't pass for a real nic
+    device_id[0] = (device_id[0] & 0xfe) | 0x02;
+    MacAddress::new(device_id)
+}
+
+// installs from before the pin and device id were random all look the
+// same to homekit, changing them would drop every pairing, so just say so
+pub fn warn_if_shared(config: &Config) {
+    if config.device_id == MacAddress::new(SHARED_DEVICE_ID) {
+        warn!("this install uses the old shared pin and device id, \
+               delete the config in the data dir and pair again to get random ones");
+    }
+}
+
+// four of [0-9A-Z], made up on first use and kept
+pub async fn setup_id(storage: &mut FileStorage) -> Result<String> {
+    if let Ok(bytes) = storage.load_bytes(SETUP_ID_KEY).await {
+        if let Ok(setup_id) = String::from_utf8(bytes) {
+            if is_setup_id(&setup_id) {
+                return Ok(setup_id);
+            }
+        }
+        warn!("replacing unreadable setup id");
+    }
+
+    let mut rng = rand::thread_rng();
+    let setup_id: String = (0..4).map(|_| {
+                                     let idx = rng.gen_range(0..SETUP_ID_CHARS.len());
+                                     SETUP_ID_CHARS[idx] as char
+                                 })
+                                 .collect();
+    storage.save_bytes(SETUP_ID_KEY, setup_id.as_bytes()).await?;
+    Ok(setup_id)
+}
+
+fn is_setup_id(setup_id: &str) -> bool {
+    setup_id.len() == 4
+        && setup_id.bytes().all(|byte| SETUP_ID_CHARS.contains(&byte))
+}
+
+// X-HM:// then the payload (category, flags and pin) in nine base 36
+// digits, then the setup id
+pub fn setup_uri(config: &Config, setup_id: &str) -> String {
+    let pin = config.pin
+                    .to_string()
+                    .chars()
+                    .filter_map(|c| c.to_digit(10))
+                    .fold(0u64, |code, digit| code * 10 + u64::from(digit));
+    format!("X-HM://{}{}", setup_payload(config.category as u64, pin), setup_id)
+}
+
+fn setup_payload(category: u64, pin: u64) -> String {
+    let mut payload = (category << 31) | (SETUP_FLAG_IP << 27) | pin;
+    let mut encoded = [b'0'; 9];
+    for digit in encoded.iter_mut().rev() {
+        *digit = SETUP_ID_CHARS[(payload % 36) as usize];
+        payload /= 36;
+    }
+    String::from_utf8_lossy(&encoded).into_owned()
+}
+
+// hap's bonjour records don't carry the setup hash (sh) the home app
+// matches a scanned code against, so the qr code only works some of the
+// time. the pin always does, so that comes first
+pub fn print(config: &Config, setup_id: &str) -> Result<()> {
+    let uri = setup_uri(config, setup_id);
+    let qr_code = QrCode::new(uri.as_bytes())?
+                         .render::<unicode::Dense1x2>()
+                         .dark_color(unicode::Dense1x2::Light)
+                         .light_color(unicode::Dense1x2::Dark)
+                         .build();
+    println!("to pair, add an accessory in the home app, pick {} from the list", config.name);
+    println!("and enter the code {}", config.pin);
+    println!();
+    println!("scanning this may work too, if the home app doesn't find {} from it", config.name);
+    println!("use the code above:");
+    println!("{}", qr_code);
+    println!("{}", uri);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // hap's AccessoryCategory::Thermostat
+    const THERMOSTAT: u64 = 9;
+
+    #[test]
+    fn setup_payload_known_answers() {
+        assert_eq!(setup_payload(THERMOSTAT, 11122333), "00909G525");
+        assert_eq!(setup_payload(THERMOSTAT, 51808582), "0090XO6RQ");
+        // lightbulb
+        assert_eq!(setup_payload(5, 11122333), "00527813X");
+    }
+
+    #[test]
+    fn setup_payload_decodes_back() {
+        let decoded = setup_payload(THERMOSTAT, 51808582)
+                          .chars()
+                          .map(|c| c.to_digit(36).unwrap() as u64)
+                          .fold(0u64, |payload, digit| payload * 36 + digit);
+        assert_eq!(decoded >> 31, THERMOSTAT);
+        assert_eq!((decoded >> 27) & 0xf, SETUP_FLAG_IP);
+        assert_eq!(decoded & ((1 << 27) - 1), 51808582);
+    }
+
+    #[test]
+    fn rejects_trivial_pins() {
+        for digit in 0..=9 {
+            assert!(is_trivial(&[digit; 8]), "all {}s", digit);
+        }
+        assert!(is_trivial(&[1, 2, 3, 4, 5, 6, 7, 8]));
+        assert!(is_trivial(&[8, 7, 6, 5, 4, 3, 2, 1]));
+    }
+
+    #[test]
+    fn keeps_other_pins() {
+        assert!(!is_trivial(&[1, 1, 1, 2, 2, 3, 3, 3]));
+        assert!(!is_trivial(&[0, 1, 2, 3, 4, 5, 6, 7]));
+        assert!(!is_trivial(&[2, 3, 4, 5, 6, 7, 8, 9]));
+        assert!(!is_trivial(&[0, 0, 0, 0, 0, 0, 0, 1]));
+    }
+
+    #[test]
+    fn setup_ids() {
+        assert!(is_setup_id("3QYT"));
+        assert!(!is_setup_id("3qyt"));
+        assert!(!is_setup_id("3QY"));
+        assert!(!is_setup_id("3QYT0"));
+    }
+}
@@ -0,0 +1,54 @@
+use std::{sync::Arc, time::Duration};
+use tokio::{
+    signal::unix::{signal, SignalKind},
+    sync,
+    task::JoinHandle,
+};
+use tracing::{info, warn};
+
+use crate::{
+    bluetooth_service::BluetoothService,
+    utils::HeatingCoolingState,
+};
+
+// each step gets this long, a wedged ble stack shouldn't keep us from exiting
+const STEP_TIMEOUT: Duration = Duration::from_secs(5);
+
+// resolves with the name of the signal once we get SIGINT or SIGTERM
+pub async fn wait_for_signal() -> &'static str {
+    let mut sigterm = signal(SignalKind::terminate()).expect("couldn't listen for SIGTERM");
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => "SIGINT",
+        _ = sigterm.recv() => "SIGTERM",
+    }
+}
+
+pub async fn shut_down(service: Arc<BluetoothService>,
+                       update_loop: JoinHandle<()>,
+                       shutdown_tx: sync::watch::Sender<bool>,
+                       should_turn_off: bool) {
+    // stop polling first so nothing new goes out over ble, the loop
+    // closes out any running session on its way out
+    let _ = shutdown_tx.send(true);
+    if tokio::time::timeout(STEP_TIMEOUT, update_loop).await.is_err() {
+        warn!("update loop didn't stop in time");
+    }
+
+    if should_turn_off {
+        info!("turning the heat and air off");
+        let turned_off = tokio::time::timeout(STEP_TIMEOUT,
+                                              service.set_curr_heat_air_state(HeatingCoolingState::Off))
+                                              .await;
+        if !matches!(turned_off, Ok(Some(Ok(())))) {
+            warn!("couldn't turn the volcano off");
+        }
+    }
+
+    // hap's FileStorage writes through on every save, so the pairings and
+    // config are already on disk by now, the ble link is the last thing open
+    match tokio::time::timeout(STEP_TIMEOUT, service.disconnect()).await {
+        Ok(Ok(())) => (),
+        Ok(Err(err)) => warn!(error = %err, "couldn't disconnect cleanly"),
+        Err(_) => warn!("timed out disconnecting"),
+    }
+}
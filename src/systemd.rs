@@ -0,0 +1,68 @@
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+use sd_notify::NotifyState;
+use tokio::{net::TcpStream, sync};
+use tracing::{info, warn};
+
+use crate::bluetooth_service::BluetoothService;
+
+// sd_notify(3) support, all of these are no-ops when not run by systemd
+
+const READY_POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+// leaves room for an answer that's stuck behind a connect
+const WATCHDOG_PINGS_PER_TIMEOUT: u32 = 4;
+
+fn notify(state: &[NotifyState]) {
+    if let Err(err) = sd_notify::notify(false, state) {
+        warn!(error = %err, "couldn't notify systemd");
+    }
+}
+
+// pings as long as the connection task keeps answering. asking whether
+// it's connected never starts a connection, but it does wait behind
+// whatever the task is on, which a connect timeout keeps well below
+// WatchdogSec. a wedged ble stack stops the answers and with them the pings
+pub async fn watchdog_loop(service: Arc<BluetoothService>,
+                           mut shutdown_rx: sync::watch::Receiver<bool>) {
+    let mut usec = 0;
+    if !sd_notify::watchdog_enabled(false, &mut usec) {
+        return;
+    }
+    let ping_interval = Duration::from_micros(usec) / WATCHDOG_PINGS_PER_TIMEOUT;
+    loop {
+        tokio::select! {
+            _ = tokio::time::sleep(ping_interval) => (),
+            _ = shutdown_rx.changed() => break,
+        }
+        service.is_connected().await;
+        notify(&[NotifyState::Watchdog]);
+    }
+}
+
+pub fn notify_stopping() {
+    notify(&[NotifyState::Stopping]);
+}
+
+// READY=1 once the hap server accepts connections, the volcano can show
+// up whenever, STATUS says whether it has. hap listens on the config's
+// host (the lan address), not on loopback, so that's what gets probed
+pub async fn notify_when_ready(hap_addr: SocketAddr, service: Arc<BluetoothService>) {
+    while TcpStream::connect(hap_addr).await.is_err() {
+        tokio::time::sleep(READY_POLL_INTERVAL).await;
+    }
+    info!(%hap_addr, "ready");
+    notify(&[NotifyState::Ready, NotifyState::Status("waiting for the volcano")]);
+
+    let mut state_rx = service.subscribe();
+    let mut was_connected = false;
+    while state_rx.changed().await.is_ok() {
+        let is_connected = state_rx.borrow_and_update().connected;
+        if is_connected == was_connected {
+            continue;
+        }
+        was_connected = is_connected;
+        let status = if is_connected { "connected to the volcano" }
+                     else { "waiting for the volcano" };
+        notify(&[NotifyState::Status(status)]);
+    }
+}
@@ -1,11 +1,20 @@
+use std::time::SystemTime;
 use bytes::{Bytes,
             Buf,
             BufMut};
 
 
+/// Equality that doesn't trip over float noise, values coming back
+/// from homekit have been through the offset and an f32 round trip.
+pub trait ApproxEq {
+    fn approx_eq(&self, other: &Self) -> bool;
+}
+
 const HEAT_ENABLED_BYTE: u8 = 0x23;
 const AIR_ENABLED_BYTE: u8 = 0x03;
 
+/// The heater and air pump, named after homekit's thermostat modes.
+/// `Cooling` is heat with the air pump running.
 #[derive(Debug, Copy, Clone, PartialEq)]
 pub enum HeatingCoolingState {
     Off,
@@ -40,6 +49,33 @@ impl HeatingCoolingState {
         }
     }
 
+    // the volcano only pumps heated air, so air on implies heat on
+    // and heat off takes the air with it
+    pub fn with_heat(on: bool, curr_state: Option<HeatingCoolingState>) -> HeatingCoolingState {
+        match (on, curr_state) {
+            (false, _) => HeatingCoolingState::Off,
+            (true, Some(HeatingCoolingState::Cooling)) => HeatingCoolingState::Cooling,
+            (true, _) => HeatingCoolingState::Heating,
+        }
+    }
+
+    pub fn with_air(on: bool, curr_state: Option<HeatingCoolingState>) -> HeatingCoolingState {
+        match (on, curr_state) {
+            (true, _) => HeatingCoolingState::Cooling,
+            (false, Some(HeatingCoolingState::Heating))
+                | (false, Some(HeatingCoolingState::Cooling)) => HeatingCoolingState::Heating,
+            (false, _) => HeatingCoolingState::Off,
+        }
+    }
+
+    pub fn is_heat_on(&self) -> bool {
+        *self != HeatingCoolingState::Off
+    }
+
+    pub fn is_air_on(&self) -> bool {
+        *self == HeatingCoolingState::Cooling
+    }
+
     pub fn homekit_val(&self) -> u8 {
         match self {
             HeatingCoolingState::Off => 0,
@@ -49,9 +85,23 @@ impl HeatingCoolingState {
     }
 }
 
+impl ApproxEq for HeatingCoolingState {
+
+    fn approx_eq(&self, other: &HeatingCoolingState) -> bool {
+        self == other
+    }
+}
+
 const TEMP_OFFSET_C: f32 = 172.2222222;
+// the volcano works in tenths of a degree
+const TEMP_TOLERANCE_C: f32 = 0.05;
 const TARG_MIN_TEMP_C: f32 = 10.0;
+// what the volcano itself will accept as a target
+pub const DEVICE_MIN_TEMP_C: f32 = 40.0;
+pub const DEVICE_MAX_TEMP_C: f32 = 230.0;
 
+/// A temperature in celsius, with conversions to what the volcano and
+/// homekit speak.
 #[derive(Debug, Copy, Clone, PartialEq)]
 pub struct Temperature {
     cel_val: f32
@@ -63,6 +113,10 @@ impl Temperature {
         Temperature { cel_val: 0.0 }
     }
 
+    pub fn from_celsius(cel_val: f32) -> Temperature {
+        Temperature { cel_val }
+    }
+
     pub fn from_device_val(vec: Vec<u8>) -> Temperature {
         let temp_val = Bytes::from(vec).get_i16_le();
         Temperature { cel_val: f32::from(temp_val) / 10.0 }
@@ -83,6 +137,14 @@ impl Temperature {
         bytes
     }
 
+    pub fn celsius(&self) -> f32 {
+        self.cel_val
+    }
+
+    pub fn is_settable(&self) -> bool {
+        (DEVICE_MIN_TEMP_C..=DEVICE_MAX_TEMP_C).contains(&self.cel_val)
+    }
+
     pub fn homekit_val(&self, should_scale: bool) -> f32 {
         let scale_factor = if should_scale { TEMP_OFFSET_C } 
                            else { 0.0 };
@@ -92,4 +154,46 @@ impl Temperature {
         }
         scaled_temp
     }
+
+    // compares on the homekit side of the offset and clamping, device
+    // temps below the homekit range all show up as the same value there
+    pub fn matches_homekit_val(&self, homekit_val: f32) -> bool {
+        (self.homekit_val(true) - homekit_val).abs() < TEMP_TOLERANCE_C
+    }
+}
+
+impl ApproxEq for Temperature {
+
+    fn approx_eq(&self, other: &Temperature) -> bool {
+        (self.cel_val - other.cel_val).abs() < TEMP_TOLERANCE_C
+    }
+}
+
+/// A snapshot of everything we know about the volcano, `None` until read.
+#[derive(Debug, Default, Copy, Clone, PartialEq)]
+pub struct DeviceState {
+    pub connected: bool,
+    /// Set while the values are left over from before (e.g. the last run)
+    /// and nothing has been read off the volcano since, to when they were
+    /// last read.
+    pub stale_since: Option<SystemTime>,
+    pub curr_temp: Option<Temperature>,
+    pub targ_temp: Option<Temperature>,
+    pub heat_air_state: Option<HeatingCoolingState>,
+}
+
+impl DeviceState {
+
+    /// Applies something just read off the volcano. The first read after
+    /// values left over from before drops all of them, not just the one it
+    /// replaces, the others would pass for fresh otherwise.
+    pub fn apply_read(&mut self, apply: impl FnOnce(&mut DeviceState)) {
+        if self.stale_since.take().is_some() {
+            *self = DeviceState {
+                connected: self.connected,
+                ..Default::default()
+            };
+        }
+        apply(self);
+    }
 }
@@ -1,4 +1,6 @@
-use std::sync::Arc;
+use std::{error::Error, sync::Arc};
+use tokio::sync;
+use tracing::{debug, info, info_span, warn, Instrument};
 use hap::{
     accessory::{
         thermostat::ThermostatAccessory,
@@ -9,93 +11,189 @@ use hap::{
     storage::{FileStorage, Storage},
     futures::{FutureExt, lock::Mutex},
     characteristic::AsyncCharacteristicCallbacks, 
-    serde_json::json,
+    serde_json::{json, Value},
+    service::HapService,
     HapType,
     Config,
-    MacAddress,
-    Pin,
 };
 
 use crate::{
     bluetooth_service::BluetoothService,
-    utils::{Temperature, HeatingCoolingState},
+    cli::IdentifyMode,
+    identify::Identifier,
+    metrics::metrics,
+    session::SessionRecorder,
+    setup_code,
+    utils::{ApproxEq, Temperature, HeatingCoolingState, DeviceState},
     Result,
 };
 
 
 const VOLCANO_NAME: &str = "Volcano";
+const NOT_CONNECTED: &str = "the volcano isn't connected";
 
+// mirrors the service's state onto the accessory and into the session
+// history, only ever reacting to changes, the polling happens elsewhere
 pub async fn char_update_loop(bluetooth_service: Arc<BluetoothService>,
-                          volcano_container: Arc<Mutex<Box<(dyn HapAccessory + 'static)>>>) {
+                          volcano_container: Arc<Mutex<Box<(dyn HapAccessory + 'static)>>>,
+                          mut sessions: SessionRecorder,
+                          mut shutdown_rx: sync::watch::Receiver<bool>) {
+    let mut state_rx = bluetooth_service.subscribe();
+    // nothing applied by this loop yet, the first pass sets everything
+    let mut applied = DeviceState::default();
     loop {
-        // wait 2 secs
-        tokio::time::sleep(tokio::time::Duration::from_secs(2)).await;
-
-        // get access to the shared volcano
-        let mut volcano = volcano_container.lock()
-                                           .await;
-        let volcano = volcano.get_mut_service(HapType::Thermostat)
-                             .unwrap();
-        
-        // read the states
-        let (heat_state_option,
-             curr_temp_option,
-             targ_temp_option) = tokio::join!(
-                                    bluetooth_service.get_curr_heat_air_state(),
-                                    bluetooth_service.get_curr_temp(),
-                                    bluetooth_service.get_targ_temp()
-                                );
-
-        // update curr heat state
-        {
-            let curr_heat_char = volcano.get_mut_characteristic(HapType::CurrentHeatingCoolingState)
-                                        .unwrap();
-            if let Some(heat_state) = heat_state_option {
-                let curr_heat_val = json!(heat_state.homekit_val());
-                println!("background write homekit curr heat: {:?}", curr_heat_val);
-                let _ = curr_heat_char.set_value(curr_heat_val).await;
-            }
-        }
-        // update target heat state
-        {
-            let targ_heat_char = volcano.get_mut_characteristic(HapType::TargetHeatingCoolingState)
-                                        .unwrap();
-            if let Some(heat_state) = heat_state_option {
-                let targ_heat_val = json!(heat_state.homekit_val());
-                println!("background write homekit targ heat: {:?}", targ_heat_val);
-                let _ = targ_heat_char.set_value(targ_heat_val).await;
-            }
-        }
-        // update current temp state
-        {
-            let curr_temp_char = volcano.get_mut_characteristic(HapType::CurrentTemperature)
-                                        .unwrap();
-            if let Some(curr_temp) = curr_temp_option {
-                let curr_temp_val = json!(curr_temp.homekit_val(true));
-                println!("background write homekit curr temp: {:?}", curr_temp_val);
-                let _ = curr_temp_char.set_value(curr_temp_val).await;
-            }
+        let state = *state_rx.borrow_and_update();
+
+        // keep track of heat sessions
+        if let Err(err) = sessions.observe(&state).await {
+            warn!(error = %err, "couldn't record session");
         }
-        // update target temp state
-        {
-            let targ_temp_char = volcano.get_mut_characteristic(HapType::TargetTemperature)
-                                        .unwrap();
-            if let Some(targ_temp) = targ_temp_option {
-                let targ_temp_val = json!(targ_temp.homekit_val(true));
-                println!("background write homekit targ temp: {:?}", targ_temp_val);
-                let _ = targ_temp_char.set_value(targ_temp_val).await;
-            }
+
+        apply_device_state(&volcano_container, &state, &applied).await;
+        applied = state;
+
+        tokio::select! {
+            changed = state_rx.changed() => if changed.is_err() { break },
+            _ = shutdown_rx.changed() => break,
         }
     }
+
+    if let Err(err) = sessions.finish().await {
+        warn!(error = %err, "couldn't record session");
+    }
+}
+
+// copies what changed since `applied` onto the accessory under one short
+// lock. nothing in here asks the accessory for its values, get_value would
+// run the on_read callbacks and with them a ble read, under the lock
+async fn apply_device_state(volcano_container: &Mutex<Box<(dyn HapAccessory + 'static)>>,
+                            state: &DeviceState,
+                            applied: &DeviceState) {
+    if state.heat_air_state == applied.heat_air_state
+        && state.curr_temp == applied.curr_temp
+        && state.targ_temp == applied.targ_temp {
+        return;
+    }
+    let mut volcano = volcano_container.lock()
+                                       .await;
+    let volcano = volcano.get_mut_service(HapType::Thermostat)
+                         .unwrap();
+    set_device_state(volcano, state, applied).await;
+}
+
+// every set_value sends an event to the controllers (and runs our own
+// on_update callbacks for the target characteristics), so only what changed
+async fn set_device_state(volcano: &mut dyn HapService,
+                          state: &DeviceState,
+                          applied: &DeviceState) {
+    if let Some(heat_state) = state.heat_air_state.filter(|_| state.heat_air_state != applied.heat_air_state) {
+        let heat_val = json!(heat_state.homekit_val());
+        set_char(volcano, HapType::CurrentHeatingCoolingState, heat_val.clone()).await;
+        set_char(volcano, HapType::TargetHeatingCoolingState, heat_val).await;
+    }
+    if let Some(curr_temp) = state.curr_temp.filter(|_| state.curr_temp != applied.curr_temp) {
+        let curr_temp_val = json!(curr_temp.homekit_val(true));
+        set_char(volcano, HapType::CurrentTemperature, curr_temp_val).await;
+    }
+    if let Some(targ_temp) = state.targ_temp.filter(|_| state.targ_temp != applied.targ_temp) {
+        let targ_temp_val = json!(targ_temp.homekit_val(true));
+        set_char(volcano, HapType::TargetTemperature, targ_temp_val).await;
+    }
 }
 
-pub fn create_volcano(bluetooth_service: Arc<BluetoothService>,
-                      ) -> Result<ThermostatAccessory> {
+async fn set_char(volcano: &mut dyn HapService, hap_type: HapType, value: Value) {
+    debug!(characteristic = ?hap_type, value = %value, "background write homekit");
+    let _ = volcano.get_mut_characteristic(hap_type)
+                   .unwrap()
+                   .set_value(value)
+                   .await;
+}
+
+// what a homekit read gets when the volcano didn't answer
+fn unanswered<T>(bluetooth_service: &BluetoothService)
+                 -> std::result::Result<Option<T>, Box<dyn Error + Send + Sync>> {
+    if bluetooth_service.state().stale_since.is_some() {
+        return Ok(None);
+    }
+    Err(NOT_CONNECTED.into())
+}
+
+// starts out showing `last_known`, set before any callbacks are in place
+// so none of it gets written to the volcano
+pub async fn create_volcano(bluetooth_service: Arc<BluetoothService>,
+                            last_known: &DeviceState,
+                            identify_mode: IdentifyMode) -> Result<ThermostatAccessory> {
     let mut volcano = ThermostatAccessory::new(1,
                                            AccessoryInformation {
                                                 name: VOLCANO_NAME.into(),
                                                 ..Default::default() 
                                            })?;
+    set_device_state(&mut volcano.thermostat, last_known, &DeviceState::default()).await;
+
+    // homekit reads go through the service's read cache, so opening the
+    // home app gets fresh values without a ble read for every characteristic.
+    // no answer from the volcano fails the read, which the home app shows
+    // as "No Response" rather than some made up value, unless there's
+    // nothing newer than the last known state yet, then that's shown
+    let local_srv_1 = Arc::clone(&bluetooth_service);
+    volcano.thermostat
+           .current_heating_cooling_state
+           .on_read_async(Some(move || {
+            let local_srv = Arc::clone(&local_srv_1);
+            async move {
+                match local_srv.get_curr_heat_air_state().await {
+                    Some(state) => Ok(Some(state.homekit_val())),
+                    None => unanswered(&local_srv),
+                }
+            }.instrument(info_span!("homekit_read",
+                                    characteristic = "current_heating_cooling_state"))
+             .boxed()
+    }));
+
+    let local_srv_1 = Arc::clone(&bluetooth_service);
+    volcano.thermostat
+           .target_heating_cooling_state
+           .on_read_async(Some(move || {
+            let local_srv = Arc::clone(&local_srv_1);
+            async move {
+                match local_srv.get_curr_heat_air_state().await {
+                    Some(state) => Ok(Some(state.homekit_val())),
+                    None => unanswered(&local_srv),
+                }
+            }.instrument(info_span!("homekit_read",
+                                    characteristic = "target_heating_cooling_state"))
+             .boxed()
+    }));
+
+    let local_srv_1 = Arc::clone(&bluetooth_service);
+    volcano.thermostat
+           .current_temperature
+           .on_read_async(Some(move || {
+            let local_srv = Arc::clone(&local_srv_1);
+            async move {
+                match local_srv.get_curr_temp().await {
+                    Some(temp) => Ok(Some(temp.homekit_val(true))),
+                    None => unanswered(&local_srv),
+                }
+            }.instrument(info_span!("homekit_read",
+                                    characteristic = "current_temperature"))
+             .boxed()
+    }));
+
+    let local_srv_1 = Arc::clone(&bluetooth_service);
+    volcano.thermostat
+           .target_temperature
+           .on_read_async(Some(move || {
+            let local_srv = Arc::clone(&local_srv_1);
+            async move {
+                match local_srv.get_targ_temp().await {
+                    Some(temp) => Ok(Some(temp.homekit_val(true))),
+                    None => unanswered(&local_srv),
+                }
+            }.instrument(info_span!("homekit_read",
+                                    characteristic = "target_temperature"))
+             .boxed()
+    }));
 
     let local_srv_1 = Arc::clone(&bluetooth_service);
     volcano.thermostat
@@ -106,10 +204,29 @@ pub fn create_volcano(bluetooth_service: Arc<BluetoothService>,
                 if old_val == new_val { return Ok(()); }
                 let local_srv = Arc::clone(&local_srv_tst);
                 let new_hc_state = HeatingCoolingState::from_homekit_val(new_val);
-                let _ = local_srv.set_curr_heat_air_state(new_hc_state)
-                                 .await;
-                Ok(())
-            }.boxed()
+
+                // the update loop setting the characteristic to what the
+                // device already reports lands here too, don't echo it back.
+                // the snapshot is what it set, and it holds the accessory
+                // lock meanwhile, so no ble read in here
+                let device_state = local_srv.state().heat_air_state;
+                if device_state.map_or(false, |state| state.approx_eq(&new_hc_state)) {
+                    debug!("device is already in this state, not writing");
+                    return Ok(());
+                }
+                info!(state = ?new_hc_state, "homekit set heat/air state");
+                metrics().inc_homekit_writes("target_heating_cooling_state");
+                // a failed write fails the homekit write along with it
+                match local_srv.set_curr_heat_air_state(new_hc_state).await {
+                    Some(Ok(())) => Ok(()),
+                    Some(Err(err)) => Err(err.into()),
+                    None => Err(NOT_CONNECTED.into()),
+                }
+            }.instrument(info_span!("homekit_update",
+                                    characteristic = "target_heating_cooling_state",
+                                    old_val,
+                                    new_val))
+             .boxed()
     }));
 
     let local_srv_1 = Arc::clone(&bluetooth_service);
@@ -122,17 +239,46 @@ pub fn create_volcano(bluetooth_service: Arc<BluetoothService>,
                 if old_val == new_val {
                     return Ok(());
                 }
-                let curr_device_temp = local_srv.get_targ_temp()
-                                                .await
-                                                .unwrap_or(Temperature::from_homekit_val(old_val, 
-                                                                                         true));
+                // the snapshot, same as for the heat/air state, but a write
+                // that's still being debounced is where the device is headed
+                let device_temp = local_srv.known_targ_temp();
                 let new_temp = Temperature::from_homekit_val(new_val, true);
 
-                // only write it if these match (device isn't updating itself)
-                if curr_device_temp.homekit_val(true) == old_val {
-                    let _ = local_srv.set_temp(new_temp)
-                                     .await;
+                // nothing to write if the device is already there, that's
+                // also what the update loop's own background writes look like
+                if device_temp.map_or(false, |temp| temp.matches_homekit_val(new_val)) {
+                    debug!("device is already at this temp, not writing");
+                    return Ok(());
                 }
+
+                // homekit and the device disagreed on where we started, the
+                // panel got there first but this write is newer, so it wins
+                if let Some(device_temp) = device_temp.filter(|temp| !temp.matches_homekit_val(old_val)) {
+                    info!(device_celsius = device_temp.celsius(),
+                          "device changed since homekit last saw it, overriding");
+                }
+                info!(celsius = new_temp.celsius(), "homekit set target temp");
+                metrics().inc_homekit_writes("target_temperature");
+                let _ = local_srv.set_temp(new_temp)
+                                 .await;
+                Ok(())
+            }.instrument(info_span!("homekit_update",
+                                    characteristic = "target_temperature",
+                                    old_val,
+                                    new_val))
+             .boxed()
+    }));
+
+    // homekit wants its answer right away, the routine takes a few seconds
+    let identifier = Arc::new(Identifier::new(Arc::clone(&bluetooth_service), identify_mode));
+    volcano.accessory_information
+           .identify
+           .on_update_async(Some(move |_: bool, _: bool| {
+            let identifier = Arc::clone(&identifier);
+            async move {
+                tokio::spawn(async move {
+                    identifier.run().await;
+                });
                 Ok(())
             }.boxed()
     }));
@@ -145,13 +291,15 @@ pub async fn get_volcano_config_from_storage(storage: &mut FileStorage) -> Resul
         Ok(mut config) => {
             config.redetermine_local_ip();
             storage.save_config(&config).await?;
+            setup_code::warn_if_shared(&config);
             Ok(config)
         },
         Err(_) => {
+            // first run, this pin and device id stick from here on
             let config = Config {
-                pin: Pin::new([7, 1, 0, 6, 9, 4, 2, 0])?,
+                pin: setup_code::new_pin()?,
                 name: VOLCANO_NAME.into(),
-                device_id: MacAddress::new([20, 20, 30, 40, 50, 60]),
+                device_id: setup_code::new_device_id(),
                 category: AccessoryCategory::Thermostat,
                 ..Default::default()
             };